@@ -0,0 +1,79 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use stun_client::*;
+
+// Counts heap allocations to compare the owned and borrowed parsers.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+fn allocations(f: impl Fn()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn sample_response() -> Vec<u8> {
+    let mut msg = Message::new(Method::Binding, Class::SuccessResponse, None);
+    msg.add(AttributeValue::XORMappedAddress(
+        "192.0.2.1:32853".parse().unwrap(),
+    ))
+    .unwrap();
+    msg.add(AttributeValue::Software(String::from("stun-client")))
+        .unwrap();
+    msg.add_message_integrity(b"password");
+    msg.add_fingerprint();
+    msg.to_raw()
+}
+
+fn parse(c: &mut Criterion) {
+    let raw = sample_response();
+
+    let owned = allocations(|| {
+        let msg = Message::from_raw(black_box(&raw)).unwrap();
+        black_box(Attribute::get_xor_mapped_address(&msg));
+    });
+    let borrowed = allocations(|| {
+        let msg = MessageRef::from_raw(black_box(&raw)).unwrap();
+        black_box(msg.get_raw_attr_value(Attribute::XORMappedAddress));
+    });
+    println!(
+        "allocations per parse: Message {}, MessageRef {}",
+        owned, borrowed
+    );
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("Message", |b| {
+        b.iter(|| {
+            let msg = Message::from_raw(black_box(&raw)).unwrap();
+            black_box(Attribute::get_xor_mapped_address(&msg))
+        })
+    });
+    group.bench_function("MessageRef", |b| {
+        b.iter(|| {
+            let msg = MessageRef::from_raw(black_box(&raw)).unwrap();
+            black_box(msg.get_attr_value(Attribute::XORMappedAddress))
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
@@ -14,7 +14,8 @@ async fn stun_binding() -> Result<(), Error> {
     let mut client = Client::new("0.0.0.0:0", None).await?;
     let res = client
         .binding_request("stun.l.google.com:19302", None)
-        .await?;
+        .await?
+        .message;
 
     let class = res.get_class();
     match class {
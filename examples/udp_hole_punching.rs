@@ -50,7 +50,7 @@ fn main() -> Result<(), Error> {
 
 async fn run(peer_name: String, opponent_name: String, redis_addr: String, stun_addr: String) {
     let t = task::spawn(async move {
-        let peer = Peer::new(String::from(peer_name), redis_addr.to_string()).await;
+        let peer = Peer::new(peer_name, redis_addr.to_string()).await;
         let (nmt, nft) = peer.nat_behavior_discovery(stun_addr).await.unwrap();
         println!(
             "{:?}: NAT Mapping Type={:?}, NAT Filtering Type={:?}",
@@ -65,13 +65,13 @@ async fn run(peer_name: String, opponent_name: String, redis_addr: String, stun_
                 addr_candidates.push(nmt.test1_xor_mapped_addr.unwrap().to_string());
             }
             NATMappingType::AddressDependent => {
-                let mut candidate = nmt.test2_xor_mapped_addr.unwrap().clone();
+                let mut candidate = nmt.test2_xor_mapped_addr.unwrap();
                 // "N+1" technique
                 candidate.set_port(candidate.port() + 1);
                 addr_candidates.push(candidate.to_string());
             }
             NATMappingType::AddressAndPortDependent => {
-                let mut candidate = nmt.test3_xor_mapped_addr.unwrap().clone();
+                let mut candidate = nmt.test3_xor_mapped_addr.unwrap();
                 // // "N+1" technique
                 candidate.set_port(candidate.port() + 1);
                 addr_candidates.push(candidate.to_string());
@@ -82,7 +82,7 @@ async fn run(peer_name: String, opponent_name: String, redis_addr: String, stun_
         }
 
         let opponent_candidates = peer
-            .signalling(String::from(opponent_name), addr_candidates)
+            .signalling(opponent_name, addr_candidates)
             .await
             .unwrap();
         let opponent_peer = peer.hole_punching(opponent_candidates).await.unwrap();
@@ -108,9 +108,9 @@ impl Peer {
         let socket = Arc::new(socket);
         let redis_client = redis::Client::open(format!("redis://{}/", redis_addr)).unwrap();
         Peer {
-            name: name,
-            socket: socket,
-            redis_client: redis_client,
+            name,
+            socket,
+            redis_client,
         }
     }
 
@@ -0,0 +1,31 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use stun_client::*;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Message::is_stun_lenient(data);
+    let msg = match Message::from_raw(data) {
+        Ok(msg) => msg,
+        Err(_) => return,
+    };
+
+    let _ = msg.get_attr_values();
+    let _ = Attribute::get_mapped_address(&msg);
+    let _ = Attribute::get_xor_mapped_address(&msg);
+    let _ = Attribute::get_other_address(&msg);
+    let _ = Attribute::get_response_origin(&msg);
+    let _ = Attribute::get_software(&msg);
+    let _ = Attribute::get_username(&msg);
+    let _ = Attribute::get_realm(&msg);
+    let _ = Attribute::get_nonce(&msg);
+    let _ = Attribute::get_userhash(&msg);
+    let _ = Attribute::get_alternate_server(&msg);
+    let _ = Attribute::get_alternate_domain(&msg);
+    let _ = Attribute::get_error_code(&msg);
+    let _ = Attribute::get_unknown_attributes(&msg);
+    let _ = Attribute::get_password_algorithms(&msg);
+    let _ = Attribute::get_password_algorithm(&msg);
+    let _ = PasswordAlgorithm::negotiate(&msg);
+    let _ = msg.verify_integrity(b"key");
+    let _ = msg.to_raw();
+});
@@ -1,12 +1,14 @@
 //! This module is a thread-safe async-std-based asynchronous STUN client.
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::fmt;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use async_macros::select;
 use async_std::future;
-use async_std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use async_std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
 use async_std::task;
 use futures::channel::mpsc;
 use futures::stream::StreamExt;
@@ -14,25 +16,296 @@ use futures::SinkExt;
 
 use super::error::*;
 use super::message::*;
+use super::request_handler::*;
+
+/// The duration of the whole RFC8489 retransmission schedule with the default RTO, Rc and Rm:
+/// the last of the 7 requests is sent after 31.5 seconds and waited for 16 times the RTO of 500 ms.
+pub const RFC8489_TRANSACTION_TIMEOUT_MS: u64 = 39500;
 
 const DEFAULT_RECV_TIMEOUT_MS: u64 = 3000;
-const DEFAULT_RECV_BUF_SIZE: usize = 1024;
+// The largest UDP payload, so that datagrams of other protocols sharing the socket are not truncated.
+const DEFAULT_RECV_BUF_SIZE: usize = 65535;
+const DEFAULT_RTO_MS: u64 = 500;
+const DEFAULT_RC: u32 = 7;
+const DEFAULT_RM: u32 = 16;
+const MAX_STALE_NONCE_RETRIES: u32 = 3;
+const MAX_TRANSACTION_ID_ATTEMPTS: u32 = 3;
+const NON_STUN_CHANNEL_SIZE: usize = 64;
+const REQUEST_EVENT_CHANNEL_SIZE: usize = 64;
+const KEEPALIVE_RESPONSE_CHANNEL_SIZE: usize = 16;
+
+type ResponseSender = mpsc::Sender<Result<(Message, SocketAddr), STUNClientError>>;
+type Transactions = Arc<Mutex<HashMap<TransactionId, Transaction>>>;
+type Datagram = (Vec<u8>, SocketAddr);
+type SharedRequestHandler = Arc<Mutex<Box<dyn RequestHandler>>>;
 
 /// STUN client options.
+///
+/// Requests are retransmitted as described in RFC8489 Section 6.2.1:
+/// the first retransmission is sent after `rto_ms`, the interval doubles after each one,
+/// at most `rc` requests are sent and the transaction fails when no response arrives
+/// within `rm` times `rto_ms` after the last request.
+/// `recv_timeout_ms` caps the duration of the whole transaction.
+/// Its default of 3 seconds cuts the schedule after the third request;
+/// set it to [`RFC8489_TRANSACTION_TIMEOUT_MS`] to wait for every retransmission.
 #[derive(Clone, Debug)]
 pub struct Options {
     pub recv_timeout_ms: u64,
+    /// Maximum size of the datagrams received on the socket. Larger datagrams are dropped.
     pub recv_buf_size: usize,
+    /// Initial retransmission timeout (RTO) in milliseconds.
+    pub rto_ms: u64,
+    /// Maximum number of requests sent in a transaction (Rc).
+    pub rc: u32,
+    /// Multiplier of the initial RTO to wait for a response after the last request (Rm).
+    pub rm: u32,
+    /// Whether to append the FINGERPRINT attribute to every request.
+    /// This is useful when STUN is multiplexed with other protocols on the same socket.
+    pub fingerprint: bool,
+    /// Long-term credentials used when the server challenges a request with 401 Unauthorized.
+    pub credentials: Option<Credentials>,
+    /// Whether to accept responses without the magic cookie from RFC3489 servers,
+    /// and the RFC3489 attributes such as SOURCE-ADDRESS and CHANGED-ADDRESS they contain.
+    pub rfc3489_compatible: bool,
+    /// Maximum number of 300 Try Alternate redirects followed by a request (RFC8489 Section 10).
+    /// Redirects are not followed if it is 0.
+    pub max_redirects: u32,
+    /// Whether to accept responses only from the address the request was sent to,
+    /// or from the alternate address expected for CHANGE-REQUEST.
+    /// Disabling it allows off-path attackers who guess a transaction ID to spoof responses.
+    pub validate_source: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            recv_timeout_ms: DEFAULT_RECV_TIMEOUT_MS,
+            recv_buf_size: DEFAULT_RECV_BUF_SIZE,
+            rto_ms: DEFAULT_RTO_MS,
+            rc: DEFAULT_RC,
+            rm: DEFAULT_RM,
+            fingerprint: false,
+            credentials: None,
+            rfc3489_compatible: false,
+            max_redirects: 0,
+            validate_source: true,
+        }
+    }
+}
+
+/// Long-term credentials (RFC8489 Section 9.2).
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+// The state learned from the last 401 or 438 response of a server.
+struct Authentication {
+    // REALM and NONCE are echoed back exactly as received.
+    realm: Vec<u8>,
+    nonce: Vec<u8>,
+    // Echoed back to the server as-is for downgrade protection.
+    password_algorithms: Option<Vec<u8>>,
+    password_algorithm: PasswordAlgorithm,
+    integrity: Attribute,
+    key: Vec<u8>,
+    // Sent instead of USERNAME when the server requests username anonymity.
+    userhash: Option<Vec<u8>>,
+}
+
+impl Authentication {
+    // Returns None if the response does not carry a long-term credential challenge.
+    fn from_challenge(
+        res: &Message,
+        credentials: &Credentials,
+    ) -> Result<Option<Self>, STUNClientError> {
+        let (realm, nonce) = match (Attribute::get_realm(res), Attribute::get_nonce(res)) {
+            (Some(realm), Some(nonce)) => (realm, nonce),
+            _ => return Ok(None),
+        };
+
+        // RFC8489: If the "Password algorithms" bit is set, PASSWORD-ALGORITHMS must be present,
+        // otherwise an attacker may have removed it to downgrade the algorithm.
+        // Without the bit the server is treated as an RFC5389 server and PASSWORD-ALGORITHMS is ignored.
+        let features = SecurityFeatures::from_nonce(&nonce).unwrap_or_default();
+        let password_algorithms = res.get_raw_attr_value(Attribute::PasswordAlgorithms);
+        let password_algorithms = match (features.password_algorithms, password_algorithms) {
+            (true, None) => {
+                return Err(STUNClientError::AuthenticationError(String::from(
+                    "PASSWORD-ALGORITHMS is missing although the nonce cookie requires it",
+                )))
+            }
+            (true, Some(password_algorithms)) => Some(password_algorithms),
+            (false, _) => None,
+        };
+
+        let (password_algorithm, integrity) = match &password_algorithms {
+            Some(_) => PasswordAlgorithm::negotiate(res)?,
+            None => (PasswordAlgorithm::MD5, Attribute::MessageIntegrity),
+        };
+        let key =
+            password_algorithm.derive_key(&credentials.username, &realm, &credentials.password)?;
+        let userhash = if features.username_anonymity {
+            Some(Attribute::generate_userhash_value(
+                &credentials.username,
+                &realm,
+            )?)
+        } else {
+            None
+        };
+
+        // REALM and NONCE have just been decoded, so their raw values are present.
+        Ok(Some(Authentication {
+            realm: res.get_raw_attr_value(Attribute::Realm).unwrap_or_default(),
+            nonce: res.get_raw_attr_value(Attribute::Nonce).unwrap_or_default(),
+            password_algorithms,
+            password_algorithm,
+            integrity,
+            key,
+            userhash,
+        }))
+    }
+
+    fn authenticate(
+        &self,
+        msg: &mut Message,
+        credentials: &Credentials,
+    ) -> Result<(), STUNClientError> {
+        match &self.userhash {
+            Some(userhash) => msg.add(AttributeValue::UserHash(userhash.clone()))?,
+            None => msg.add(AttributeValue::Username(credentials.username.clone()))?,
+        }
+        // The values of the server are not prepared again and may exceed the limits of values sent by the client.
+        msg.add(AttributeValue::Opaque(Attribute::Realm, self.realm.clone()))?;
+        msg.add(AttributeValue::Opaque(Attribute::Nonce, self.nonce.clone()))?;
+        if let Some(password_algorithms) = &self.password_algorithms {
+            msg.add(AttributeValue::Opaque(
+                Attribute::PasswordAlgorithms,
+                password_algorithms.clone(),
+            ))?;
+            msg.add(AttributeValue::PasswordAlgorithm(self.password_algorithm))?;
+        }
+
+        match self.integrity {
+            Attribute::MessageIntegritySHA256 => msg.add_message_integrity_sha256(&self.key),
+            _ => msg.add_message_integrity(&self.key),
+        }
+        Ok(())
+    }
+}
+
+// An outstanding transaction waiting for its response.
+struct Transaction {
+    // The address the response must come from. None matches any IP address or port.
+    source_ip: Option<IpAddr>,
+    source_port: Option<u16>,
+    tx: ResponseSender,
+}
+
+impl Transaction {
+    fn accepts(&self, source: SocketAddr) -> bool {
+        self.source_ip.map_or(true, |ip| ip == source.ip())
+            && self.source_port.map_or(true, |port| port == source.port())
+    }
+}
+
+/// The result of a STUN transaction.
+#[derive(Clone, Debug)]
+pub struct Response {
+    /// The response message.
+    pub message: Message,
+    /// The server that answered the request, which differs from the requested one after redirects.
+    pub server: SocketAddr,
+    /// The ALTERNATE-DOMAIN of the last 300 Try Alternate response followed to reach the server, if any.
+    /// It names the server for certificate validation over TLS or DTLS.
+    pub alternate_domain: Option<String>,
+    /// The address the response came from.
+    /// It differs from the server when CHANGE-REQUEST asks the server to answer from its alternate address.
+    pub source: SocketAddr,
+    /// The local address of the socket the request was sent from.
+    pub local: SocketAddr,
+    /// The number of times the request was retransmitted before the response arrived.
+    pub retransmissions: u32,
+    /// The round-trip time measured from the last transmission of the request.
+    /// It may be underestimated if the response answers an earlier transmission.
+    pub rtt: Duration,
+}
+
+/// The message sent periodically by a keepalive task.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Keepalive {
+    /// Binding indications, which are not answered.
+    Indication,
+    /// Binding requests, whose responses can be used to track changes of the mapped address.
+    Request,
+}
+
+/// Handle of a keepalive task started by [`Client::start_keepalive`].
+/// The task stops when the handle is dropped, when stop is called or when the Client is dropped.
+pub struct KeepaliveHandle {
+    stop_tx: mpsc::Sender<bool>,
+    responses: Option<mpsc::Receiver<Result<Response, STUNClientError>>>,
+}
+
+impl KeepaliveHandle {
+    /// Stops the keepalive task. A request in progress is completed first.
+    pub fn stop(self) {
+        // The task stops when the channel is closed.
+        drop(self.stop_tx);
+    }
+
+    /// Returns the stream of the results of keepalive requests.
+    /// The stream can be taken only once, later calls return None.
+    /// Nothing is sent on it for indications, and results are dropped while it is full.
+    pub fn responses(&mut self) -> Option<mpsc::Receiver<Result<Response, STUNClientError>>> {
+        self.responses.take()
+    }
 }
 
 /// STUN client.
 /// The transport protocol is UDP only and only supports simple STUN Binding requests.
+/// Binding requests received on the socket are answered by the request handler, see [`Client::set_request_handler`].
 pub struct Client {
     socket: Arc<UdpSocket>,
-    recv_timeout_ms: u64,
-    transactions: Arc<Mutex<HashMap<Vec<u8>, mpsc::Sender<Result<Message, STUNClientError>>>>>,
+    opts: Arc<Options>,
+    transactions: Transactions,
     running: Arc<AtomicBool>,
     stop_tx: mpsc::Sender<bool>,
+    // The long-term credential state of each server that challenged a request.
+    // Requests to other servers are sent without credentials.
+    auths: HashMap<SocketAddr, Authentication>,
+    // OTHER-ADDRESS learned from each server, used to validate the source of CHANGE-REQUEST responses.
+    other_addresses: HashMap<SocketAddr, SocketAddr>,
+    non_stun_rx: Option<mpsc::Receiver<Datagram>>,
+    request_handler: SharedRequestHandler,
+    request_rx: Option<mpsc::Receiver<RequestEvent>>,
+}
+
+// Routes the datagrams received on the socket.
+struct Dispatcher {
+    socket: Arc<UdpSocket>,
+    transactions: Transactions,
+    non_stun_tx: mpsc::Sender<Datagram>,
+    request_handler: SharedRequestHandler,
+    request_tx: mpsc::Sender<RequestEvent>,
+}
+
+// The parts of a Client needed to run transactions, shared with its background tasks.
+#[derive(Clone)]
+struct Transport {
+    socket: Arc<UdpSocket>,
+    opts: Arc<Options>,
+    transactions: Transactions,
 }
 
 impl Client {
@@ -43,177 +316,601 @@ impl Client {
     ) -> Result<Client, STUNClientError> {
         let socket = UdpSocket::bind(local_bind_addr)
             .await
-            .map_err(|e| STUNClientError::IOError(e))?;
-        let socket = Arc::new(socket);
-        let transactions = Arc::new(Mutex::new(HashMap::new()));
-        let running = Arc::new(AtomicBool::new(true));
-        let (tx, rx) = mpsc::channel(1);
-        let recv_timeout_ms = opts
-            .clone()
-            .map(|o| o.recv_timeout_ms)
-            .unwrap_or_else(|| DEFAULT_RECV_TIMEOUT_MS);
-        let client = Client {
-            socket: socket.clone(),
-            recv_timeout_ms: recv_timeout_ms,
-            transactions: transactions.clone(),
-            running: running.clone(),
-            stop_tx: tx,
-        };
-
-        let recv_buf_size = opts
-            .map(|o| o.recv_buf_size)
-            .unwrap_or_else(|| DEFAULT_RECV_BUF_SIZE);
-        task::spawn(async move {
-            Self::run_message_receiver(socket, recv_buf_size, running, rx, transactions).await
-        });
-        Ok(client)
+            .map_err(STUNClientError::IOError)?;
+        Ok(Self::from_socket(Arc::new(socket), opts))
     }
 
     /// Create a Client from async_std::net::UdpSocket.
     pub fn from_socket(socket: Arc<UdpSocket>, opts: Option<Options>) -> Client {
+        let opts = Arc::new(opts.unwrap_or_default());
         let transactions = Arc::new(Mutex::new(HashMap::new()));
         let running = Arc::new(AtomicBool::new(true));
         let (tx, rx) = mpsc::channel(1);
-        let recv_timeout_ms = opts
-            .clone()
-            .map(|o| o.recv_timeout_ms)
-            .unwrap_or_else(|| DEFAULT_RECV_TIMEOUT_MS);
+        let (non_stun_tx, non_stun_rx) = mpsc::channel(NON_STUN_CHANNEL_SIZE);
+        let (request_tx, request_rx) = mpsc::channel(REQUEST_EVENT_CHANNEL_SIZE);
+        let request_handler: SharedRequestHandler =
+            Arc::new(Mutex::new(Box::new(BindingRequestHandler::new())));
+        let recv_buf_size = opts.recv_buf_size;
+        let rfc3489_compatible = opts.rfc3489_compatible;
         let client = Client {
             socket: socket.clone(),
-            recv_timeout_ms: recv_timeout_ms,
+            opts,
             transactions: transactions.clone(),
             running: running.clone(),
             stop_tx: tx,
+            auths: HashMap::new(),
+            other_addresses: HashMap::new(),
+            non_stun_rx: Some(non_stun_rx),
+            request_handler: request_handler.clone(),
+            request_rx: Some(request_rx),
+        };
+        let dispatcher = Dispatcher {
+            socket,
+            transactions,
+            non_stun_tx,
+            request_handler,
+            request_tx,
         };
 
-        let recv_buf_size = opts
-            .map(|o| o.recv_buf_size)
-            .unwrap_or_else(|| DEFAULT_RECV_BUF_SIZE);
         task::spawn(async move {
-            Self::run_message_receiver(socket, recv_buf_size, running, rx, transactions).await
+            Self::run_message_receiver(dispatcher, recv_buf_size, rfc3489_compatible, running, rx)
+                .await
         });
         client
     }
 
+    /// Send STUN Binding indication asynchronously.
+    /// Indications are not answered, so this returns once the message is sent.
+    /// FINGERPRINT is added if it is set in Options.
+    pub async fn send_indication<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        attrs: Option<Vec<AttributeValue>>,
+    ) -> Result<(), STUNClientError> {
+        let addr = resolve(addr).await?;
+        self.transport()
+            .send_indication(addr, &attrs.unwrap_or_default())
+            .await
+    }
+
+    /// Starts a background task that sends Binding indications or requests to the address every interval,
+    /// keeping the NAT mapping of the socket open for long-lived flows.
+    /// Keepalive requests do not use the credentials in Options.
+    pub async fn start_keepalive<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        interval: Duration,
+        keepalive: Keepalive,
+    ) -> Result<KeepaliveHandle, STUNClientError> {
+        let addr = resolve(addr).await?;
+        let (stop_tx, stop_rx) = mpsc::channel(1);
+        let (responses_tx, responses_rx) = mpsc::channel(KEEPALIVE_RESPONSE_CHANNEL_SIZE);
+        let transport = self.transport();
+        let running = self.running.clone();
+        task::spawn(async move {
+            transport
+                .run_keepalive(addr, interval, keepalive, running, stop_rx, responses_tx)
+                .await
+        });
+        Ok(KeepaliveHandle {
+            stop_tx,
+            responses: Some(responses_rx),
+        })
+    }
+
+    /// Replaces the handler of STUN requests received on the socket.
+    /// By default, Binding requests are answered by [`BindingRequestHandler::new`].
+    pub fn set_request_handler<H: RequestHandler + 'static>(&self, handler: H) {
+        // It's a bug if you panic with this unwrap
+        *self.request_handler.lock().unwrap() = Box::new(handler);
+    }
+
+    /// Returns the stream of STUN requests received on the socket, with the responses sent by the request handler.
+    /// The stream can be taken only once, later calls return None.
+    /// Requests are still answered while the stream is full or if it is never taken, but their events are dropped.
+    pub fn request_receiver(&mut self) -> Option<mpsc::Receiver<RequestEvent>> {
+        self.request_rx.take()
+    }
+
+    /// Returns the stream of datagrams received on the socket that are not STUN messages,
+    /// with the addresses they came from, so that the socket can be shared with other protocols.
+    /// The stream can be taken only once, later calls return None.
+    /// Datagrams are dropped while the stream is full or if it is never taken.
+    /// Datagrams larger than recv_buf_size in Options are dropped rather than truncated.
+    pub fn non_stun_receiver(&mut self) -> Option<mpsc::Receiver<(Vec<u8>, SocketAddr)>> {
+        self.non_stun_rx.take()
+    }
+
     /// Send STUN Binding request asynchronously.
+    /// If credentials are set in Options, 401 Unauthorized and 438 Stale Nonce responses are answered
+    /// by resending the request with the long-term credentials, and the integrity of the success response is verified.
+    /// The realm and nonce are kept for each server, so requests to servers that did not challenge the client
+    /// are sent and answered without credentials.
+    /// A response containing comprehension-required attributes that are not understood fails the request
+    /// as described in RFC8489 Section 7.3.3.
+    /// If max_redirects is set in Options, 300 Try Alternate responses are followed to their ALTERNATE-SERVER,
+    /// which must have the address family of the socket.
     pub async fn binding_request<A: ToSocketAddrs>(
         &mut self,
         stun_addr: A,
-        attrs: Option<HashMap<Attribute, Vec<u8>>>,
-    ) -> Result<Message, STUNClientError> {
-        let msg = Message::new(Method::Binding, Class::Request, attrs);
-        let (tx, mut rx) = mpsc::channel(1);
-        {
-            let mut m = self.transactions.lock().unwrap();
-            m.insert(msg.get_transaction_id(), tx);
+        attrs: Option<Vec<AttributeValue>>,
+    ) -> Result<Response, STUNClientError> {
+        // Resolve once so that all requests of the exchange go to the same server.
+        let stun_addr = resolve(stun_addr).await?;
+
+        let mut servers = vec![stun_addr];
+        let mut alternate_domain = None;
+        loop {
+            let server = servers[servers.len() - 1];
+            let mut res = self.binding_transaction(server, attrs.clone()).await?;
+            res.alternate_domain = alternate_domain;
+            if let Some(other_addr) = Attribute::get_other_address(&res.message) {
+                self.other_addresses.insert(server, other_addr);
+            }
+            if self.opts.max_redirects == 0
+                || !matches!(
+                    Attribute::get_error_code(&res.message),
+                    Some(ErrorCode::TryAlternate(_))
+                )
+            {
+                return Ok(res);
+            }
+
+            // RFC8489: The 300 response must be authenticated if the request was,
+            // otherwise an attacker could redirect the client to an arbitrary server.
+            if let Some(auth) = self.auths.get(&server) {
+                res.message.verify_integrity(&auth.key)?;
+            }
+            let alternate = match Attribute::get_alternate_server(&res.message) {
+                Some(alternate) => alternate,
+                None => return Ok(res),
+            };
+            if servers.contains(&alternate) {
+                return Err(STUNClientError::RedirectError(format!(
+                    "redirect loop detected at {}",
+                    alternate
+                )));
+            }
+            let local = self.socket.local_addr().map_err(STUNClientError::IOError)?;
+            if alternate.is_ipv4() != local.is_ipv4() {
+                return Err(STUNClientError::RedirectError(format!(
+                    "alternate server {} does not match the address family of {}",
+                    alternate, local
+                )));
+            }
+            if servers.len() > self.opts.max_redirects as usize {
+                return Err(STUNClientError::RedirectError(format!(
+                    "more than {} redirects",
+                    self.opts.max_redirects
+                )));
+            }
+
+            alternate_domain = Attribute::get_alternate_domain(&res.message);
+            servers.push(alternate);
         }
-        let raw_msg = msg.to_raw();
-        self.socket
-            .send_to(&raw_msg, stun_addr)
-            .await
-            .map_err(|e| STUNClientError::IOError(e))?;
+    }
+
+    // Sends a Binding request to the server, answering authentication challenges.
+    async fn binding_transaction(
+        &mut self,
+        stun_addr: SocketAddr,
+        attrs: Option<Vec<AttributeValue>>,
+    ) -> Result<Response, STUNClientError> {
+        let mut challenged = false;
+        let mut stale_nonce_retries = 0;
+        loop {
+            let res = self.send_request(stun_addr, attrs.clone()).await?;
+            let unknown_attrs = if self.opts.rfc3489_compatible {
+                res.message
+                    .get_unknown_comprehension_required_attrs_lenient()
+            } else {
+                res.message.get_unknown_comprehension_required_attrs()
+            };
+            if !unknown_attrs.is_empty() {
+                return Err(STUNClientError::UnknownAttributesError(unknown_attrs));
+            }
 
-        let fut = rx.next();
-        let res = future::timeout(Duration::from_millis(self.recv_timeout_ms), fut)
+            let credentials = match &self.opts.credentials {
+                Some(credentials) => credentials,
+                None => return Ok(res),
+            };
+
+            if res.message.get_class() != Class::ErrorResponse {
+                // Only the responses of a server that challenged the client are authenticated.
+                if let Some(auth) = self.auths.get(&stun_addr) {
+                    res.message.verify_integrity(&auth.key)?;
+                }
+                return Ok(res);
+            }
+
+            match Attribute::get_error_code(&res.message) {
+                Some(ErrorCode::Unauthorized(_)) if !challenged => challenged = true,
+                Some(ErrorCode::StaleNonce(_))
+                    if self.auths.contains_key(&stun_addr)
+                        && stale_nonce_retries < MAX_STALE_NONCE_RETRIES =>
+                {
+                    stale_nonce_retries += 1
+                }
+                _ => return Ok(res),
+            }
+
+            match Authentication::from_challenge(&res.message, credentials)? {
+                Some(auth) => {
+                    self.auths.insert(stun_addr, auth);
+                }
+                None => return Ok(res),
+            }
+        }
+    }
+
+    async fn send_request(
+        &self,
+        stun_addr: SocketAddr,
+        attrs: Option<Vec<AttributeValue>>,
+    ) -> Result<Response, STUNClientError> {
+        let attrs = attrs.unwrap_or_default();
+        let (source_ip, source_port) = self.expected_source(stun_addr, &attrs);
+        self.transport()
+            .send_request(stun_addr, source_ip, source_port, || {
+                self.build_request(stun_addr, &attrs)
+            })
             .await
-            .map_err(|_| STUNClientError::TimeoutError())?
-            .ok_or(STUNClientError::Unknown(String::from(
-                "Receive stream terminated unintentionally",
-            )))?;
+    }
 
-        {
-            let mut m = self.transactions.lock().unwrap();
-            m.remove(&msg.get_transaction_id());
+    fn transport(&self) -> Transport {
+        Transport {
+            socket: self.socket.clone(),
+            opts: self.opts.clone(),
+            transactions: self.transactions.clone(),
+        }
+    }
+
+    // Returns the IP address and port the response is expected to come from.
+    fn expected_source(
+        &self,
+        stun_addr: SocketAddr,
+        attrs: &[AttributeValue],
+    ) -> (Option<IpAddr>, Option<u16>) {
+        if !self.opts.validate_source {
+            return (None, None);
         }
 
-        res
+        let (change_ip, change_port) = attrs
+            .iter()
+            .find_map(|value| match value {
+                AttributeValue::ChangeRequest {
+                    change_ip,
+                    change_port,
+                } => Some((*change_ip, *change_port)),
+                _ => None,
+            })
+            .unwrap_or((false, false));
+
+        // RFC5780: The response to CHANGE-REQUEST is sent from the IP address and/or port of OTHER-ADDRESS.
+        // If OTHER-ADDRESS of the server is not known yet, any IP address or port is accepted.
+        let other_addr = self.other_addresses.get(&stun_addr);
+        let source_ip = match (change_ip, other_addr) {
+            (false, _) => Some(stun_addr.ip()),
+            (true, other_addr) => other_addr.map(|addr| addr.ip()),
+        };
+        let source_port = match (change_port, other_addr) {
+            (false, _) => Some(stun_addr.port()),
+            (true, other_addr) => other_addr.map(|addr| addr.port()),
+        };
+        (source_ip, source_port)
+    }
+
+    fn build_request(
+        &self,
+        stun_addr: SocketAddr,
+        attrs: &[AttributeValue],
+    ) -> Result<Message, STUNClientError> {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        for value in attrs.iter() {
+            msg.add(value.clone())?;
+        }
+        if let (Some(auth), Some(credentials)) =
+            (self.auths.get(&stun_addr), &self.opts.credentials)
+        {
+            auth.authenticate(&mut msg, credentials)?;
+        }
+        if self.opts.fingerprint {
+            msg.add_fingerprint();
+        }
+        Ok(msg)
     }
 
     async fn run_message_receiver(
-        socket: Arc<UdpSocket>,
+        mut dispatcher: Dispatcher,
         recv_buf_size: usize,
+        rfc3489_compatible: bool,
         running: Arc<AtomicBool>,
         rx: mpsc::Receiver<bool>,
-        transactions: Arc<Mutex<HashMap<Vec<u8>, mpsc::Sender<Result<Message, STUNClientError>>>>>,
     ) {
         let mut rx = rx;
+        // One more byte than the maximum size tells truncated datagrams apart.
+        let mut buf = vec![0u8; recv_buf_size + 1];
         while running.load(Ordering::Relaxed) {
-            let mut buf = vec![0u8; recv_buf_size];
-            let sock_fut = Self::socket_recv(socket.clone(), &mut buf);
+            let sock_fut = Self::socket_recv(dispatcher.socket.clone(), &mut buf);
             let stop_fut = Self::stop_recv(&mut rx);
             let result = select!(sock_fut, stop_fut).await;
 
-            let socket_recv_result;
-            match result {
-                Event::Stop(_) => return,
-                Event::Socket(ev) => {
-                    socket_recv_result = ev;
-                }
-            }
+            let socket_recv_result = match result {
+                Event::Stop => return,
+                Event::Socket(ev) => ev,
+            };
 
-            let result = socket_recv_result.map_err(|e| STUNClientError::IOError(e));
+            let result = socket_recv_result.map_err(STUNClientError::IOError);
             match result {
-                Ok(result) => {
-                    let msg = Message::from_raw(&buf[..result.0]);
+                Ok((len, _)) if len > recv_buf_size => continue,
+                Ok((len, source)) => {
+                    // RFC7983: Datagrams that are not STUN messages belong to the other protocols sharing the socket.
+                    let is_stun = if rfc3489_compatible {
+                        Message::is_stun_lenient(&buf[..len])
+                    } else {
+                        Message::is_stun(&buf[..len])
+                    };
+                    if !is_stun {
+                        dispatcher
+                            .non_stun_tx
+                            .try_send((buf[..len].to_vec(), source))
+                            .ok();
+                        continue;
+                    }
+
+                    let msg = if rfc3489_compatible {
+                        Message::from_raw_lenient(&buf[..len])
+                    } else {
+                        Message::from_raw(&buf[..len])
+                    };
                     match msg {
+                        Ok(msg) if msg.get_class() == Class::Request => {
+                            dispatcher.handle_request(msg, source).await
+                        }
                         Ok(msg) => {
-                            let tx: Option<mpsc::Sender<Result<Message, STUNClientError>>>;
+                            let tx: Option<ResponseSender>;
                             {
                                 // It's a bug if you panic with this unwrap
-                                let transactions = transactions.lock().unwrap();
+                                let transactions = dispatcher.transactions.lock().unwrap();
+                                // Responses from unexpected sources are dropped as possibly spoofed.
                                 tx = transactions
                                     .get(&msg.get_transaction_id())
-                                    .map(|v| v.clone());
+                                    .filter(|t| t.accepts(source))
+                                    .map(|t| t.tx.clone());
                             }
                             if let Some(mut tx) = tx {
-                                tx.send(Ok(msg)).await.ok();
+                                tx.send(Ok((msg, source))).await.ok();
                             }
                         }
                         Err(e) => {
-                            let transactions_unlocked: Option<
-                                HashMap<Vec<u8>, mpsc::Sender<Result<Message, STUNClientError>>>,
-                            >;
-                            {
-                                // It's a bug if you panic with this unwrap
-                                let t = transactions.lock().unwrap();
-                                transactions_unlocked = Some(t.clone());
-                            }
-                            if let Some(transactions_unlocked) = transactions_unlocked {
-                                for (_, transaction) in transactions_unlocked.iter() {
-                                    let mut transaction = transaction.clone();
-                                    transaction.send(Err(e.clone())).await.ok();
-                                }
-                            }
-                        }
-                    }
-                }
-                Err(e) => {
-                    let transactions_unlocked: Option<
-                        HashMap<Vec<u8>, mpsc::Sender<Result<Message, STUNClientError>>>,
-                    >;
-                    {
-                        // It's a bug if you panic with this unwrap
-                        let t = transactions.lock().unwrap();
-                        transactions_unlocked = Some(t.clone());
-                    }
-                    if let Some(transactions_unlocked) = transactions_unlocked {
-                        for transaction in transactions_unlocked.iter() {
-                            let mut transaction = transaction.1.clone();
-                            transaction.send(Err(e.clone())).await.ok();
+                            Self::broadcast_error(&dispatcher.transactions, e, Some(source)).await
                         }
                     }
                 }
+                Err(e) => Self::broadcast_error(&dispatcher.transactions, e, None).await,
             }
         }
     }
 
+    // Sends the error to the transactions accepting responses from the source, or to all of them without a source.
+    async fn broadcast_error(
+        transactions: &Transactions,
+        e: STUNClientError,
+        source: Option<SocketAddr>,
+    ) {
+        let transactions_unlocked: Vec<ResponseSender>;
+        {
+            // It's a bug if you panic with this unwrap
+            let t = transactions.lock().unwrap();
+            transactions_unlocked = t
+                .values()
+                .filter(|t| source.map_or(true, |source| t.accepts(source)))
+                .map(|t| t.tx.clone())
+                .collect();
+        }
+        for mut transaction in transactions_unlocked {
+            transaction.send(Err(e.clone())).await.ok();
+        }
+    }
+
     async fn socket_recv(socket: Arc<UdpSocket>, buf: &mut [u8]) -> Event {
         let result = socket.recv_from(buf).await;
         Event::Socket(result)
     }
 
     async fn stop_recv(rx: &mut mpsc::Receiver<bool>) -> Event {
-        Event::Stop(rx.next().await.unwrap_or_else(|| true))
+        rx.next().await;
+        Event::Stop
+    }
+}
+
+impl Transport {
+    async fn send_indication(
+        &self,
+        addr: SocketAddr,
+        attrs: &[AttributeValue],
+    ) -> Result<(), STUNClientError> {
+        let mut msg = Message::new(Method::Binding, Class::Indication, None);
+        for value in attrs.iter() {
+            msg.add(value.clone())?;
+        }
+        if self.opts.fingerprint {
+            msg.add_fingerprint();
+        }
+        self.socket
+            .send_to(&msg.to_raw(), addr)
+            .await
+            .map_err(STUNClientError::IOError)?;
+        Ok(())
+    }
+
+    async fn run_keepalive(
+        self,
+        addr: SocketAddr,
+        interval: Duration,
+        keepalive: Keepalive,
+        running: Arc<AtomicBool>,
+        mut stop_rx: mpsc::Receiver<bool>,
+        mut responses_tx: mpsc::Sender<Result<Response, STUNClientError>>,
+    ) {
+        let (source_ip, source_port) = if self.opts.validate_source {
+            (Some(addr.ip()), Some(addr.port()))
+        } else {
+            (None, None)
+        };
+        while running.load(Ordering::Relaxed) {
+            match keepalive {
+                // A failure to send is retried at the next interval.
+                Keepalive::Indication => {
+                    self.send_indication(addr, &[]).await.ok();
+                }
+                Keepalive::Request => {
+                    let res = self
+                        .send_request(addr, source_ip, source_port, || {
+                            let mut msg = Message::new(Method::Binding, Class::Request, None);
+                            if self.opts.fingerprint {
+                                msg.add_fingerprint();
+                            }
+                            Ok(msg)
+                        })
+                        .await;
+                    responses_tx.try_send(res).ok();
+                }
+            }
+
+            // The channel is closed when the handle is dropped.
+            let sleep = async {
+                task::sleep(interval).await;
+                false
+            };
+            let stop = async {
+                stop_rx.next().await;
+                true
+            };
+            if select!(sleep, stop).await {
+                return;
+            }
+        }
+    }
+
+    // Sends a request built by the closure, which is called again if its transaction ID collides
+    // with an outstanding transaction, and waits for the response from the expected source.
+    async fn send_request(
+        &self,
+        stun_addr: SocketAddr,
+        source_ip: Option<IpAddr>,
+        source_port: Option<u16>,
+        build_request: impl Fn() -> Result<Message, STUNClientError>,
+    ) -> Result<Response, STUNClientError> {
+        let (tx, rx) = mpsc::channel(1);
+        let mut transaction = Some(Transaction {
+            source_ip,
+            source_port,
+            tx,
+        });
+        let mut msg = build_request()?;
+        // The transaction ID must not be shared with an outstanding transaction,
+        // otherwise its response would be routed to the wrong request.
+        let mut attempts = 1;
+        loop {
+            {
+                let mut m = self.transactions.lock().unwrap();
+                if let Entry::Vacant(entry) = m.entry(msg.get_transaction_id()) {
+                    entry.insert(transaction.take().unwrap());
+                    break;
+                }
+            }
+            if attempts == MAX_TRANSACTION_ID_ATTEMPTS {
+                return Err(STUNClientError::Unknown(String::from(
+                    "failed to generate a unique transaction ID",
+                )));
+            }
+            attempts += 1;
+            msg = build_request()?;
+        }
+
+        let res = self
+            .send_with_retransmission(&msg.to_raw(), stun_addr, rx)
+            .await;
+
+        {
+            let mut m = self.transactions.lock().unwrap();
+            m.remove(&msg.get_transaction_id());
+        }
+
+        res
+    }
+
+    async fn send_with_retransmission(
+        &self,
+        raw_msg: &[u8],
+        stun_addr: SocketAddr,
+        mut rx: mpsc::Receiver<Result<(Message, SocketAddr), STUNClientError>>,
+    ) -> Result<Response, STUNClientError> {
+        let local = self.socket.local_addr().map_err(STUNClientError::IOError)?;
+        let deadline = Instant::now() + Duration::from_millis(self.opts.recv_timeout_ms);
+        let rc = self.opts.rc.max(1);
+        let mut rto = Duration::from_millis(self.opts.rto_ms);
+        for sent in 1..=rc {
+            let sent_at = Instant::now();
+            self.socket
+                .send_to(raw_msg, stun_addr)
+                .await
+                .map_err(STUNClientError::IOError)?;
+
+            // RFC8489: If, after the last request, a duration equal to Rm times the RTO has passed without a response,
+            // the client SHOULD consider the transaction to have failed.
+            let wait = if sent == rc {
+                Duration::from_millis(self.opts.rto_ms * self.opts.rm as u64)
+            } else {
+                rto
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match future::timeout(wait.min(remaining), rx.next()).await {
+                Ok(Some(res)) => {
+                    let (message, source) = res?;
+                    return Ok(Response {
+                        message,
+                        server: stun_addr,
+                        alternate_domain: None,
+                        source,
+                        local,
+                        retransmissions: sent - 1,
+                        rtt: sent_at.elapsed(),
+                    });
+                }
+                Ok(None) => {
+                    return Err(STUNClientError::Unknown(String::from(
+                        "Receive stream terminated unintentionally",
+                    )))
+                }
+                Err(_) if remaining <= wait => break,
+                Err(_) => rto *= 2,
+            }
+        }
+
+        Err(STUNClientError::TimeoutError())
+    }
+}
+
+impl Dispatcher {
+    async fn handle_request(&mut self, request: Message, source: SocketAddr) {
+        let response;
+        {
+            // It's a bug if you panic with this unwrap
+            let mut handler = self.request_handler.lock().unwrap();
+            response = handler.handle(&request, source);
+        }
+        if let Some(response) = &response {
+            self.socket.send_to(&response.to_raw(), source).await.ok();
+        }
+        self.request_tx
+            .try_send(RequestEvent {
+                request,
+                source,
+                response,
+            })
+            .ok();
     }
 }
 
@@ -227,7 +924,1036 @@ impl Drop for Client {
     }
 }
 
+async fn resolve<A: ToSocketAddrs>(addr: A) -> Result<SocketAddr, STUNClientError> {
+    addr.to_socket_addrs()
+        .await
+        .map_err(STUNClientError::IOError)?
+        .next()
+        .ok_or_else(|| {
+            STUNClientError::IOError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "no addresses to send data to",
+            ))
+        })
+}
+
 enum Event {
     Socket(Result<(usize, SocketAddr), std::io::Error>),
-    Stop(bool),
+    Stop,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    const TEST_RTO_MS: u64 = 100;
+
+    // Retransmission timers short enough for tests: requests are sent at 0, 100 and 300 ms
+    // and the transaction fails at 700 ms.
+    fn options() -> Options {
+        Options {
+            recv_timeout_ms: 5000,
+            rto_ms: TEST_RTO_MS,
+            rc: 3,
+            rm: 4,
+            ..Options::default()
+        }
+    }
+
+    async fn bind() -> UdpSocket {
+        UdpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    async fn recv_message(socket: &UdpSocket) -> (Message, SocketAddr) {
+        let mut buf = vec![0u8; DEFAULT_RECV_BUF_SIZE];
+        let (len, source) = socket.recv_from(&mut buf).await.unwrap();
+        (Message::from_raw(&buf[..len]).unwrap(), source)
+    }
+
+    // Returns the messages received until the socket is idle for a while.
+    async fn drain(socket: &UdpSocket) -> Vec<Message> {
+        let mut messages = vec![];
+        while let Ok((msg, _)) =
+            future::timeout(Duration::from_millis(300), recv_message(socket)).await
+        {
+            messages.push(msg);
+        }
+        messages
+    }
+
+    // Answers every request received on the socket with the response built by the closure, if any.
+    fn serve<F>(socket: UdpSocket, respond: F)
+    where
+        F: Fn(&Message, SocketAddr) -> Option<Message> + Send + 'static,
+    {
+        task::spawn(async move {
+            loop {
+                let (request, source) = recv_message(&socket).await;
+                if let Some(response) = respond(&request, source) {
+                    socket.send_to(&response.to_raw(), source).await.ok();
+                }
+            }
+        });
+    }
+
+    fn success_response(request: &Message, mapped_addr: SocketAddr) -> Message {
+        MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+            .transaction_id(request.get_transaction_id())
+            .attr(AttributeValue::XORMappedAddress(mapped_addr))
+            .build()
+            .unwrap()
+    }
+
+    fn credentials() -> Credentials {
+        Credentials {
+            username: String::from("user"),
+            password: String::from("password"),
+        }
+    }
+
+    // A 401 Unauthorized or 438 Stale Nonce response for the realm "realm".
+    fn challenge(
+        request: &Message,
+        error_code: ErrorCode,
+        nonce: &str,
+        password_algorithms: Option<Vec<PasswordAlgorithm>>,
+    ) -> Message {
+        let mut builder = MessageBuilder::new(Method::Binding, Class::ErrorResponse)
+            .transaction_id(request.get_transaction_id())
+            .attr(AttributeValue::ErrorCode {
+                code: error_code.code(),
+                reason: String::from(error_code.reason()),
+            })
+            .attr(AttributeValue::Realm(String::from("realm")))
+            .attr(AttributeValue::Nonce(String::from(nonce)));
+        if let Some(password_algorithms) = password_algorithms {
+            builder = builder.attr(AttributeValue::PasswordAlgorithms(password_algorithms));
+        }
+        builder.build().unwrap()
+    }
+
+    fn unauthorized() -> ErrorCode {
+        ErrorCode::Unauthorized(String::from("Unauthorized"))
+    }
+
+    // Answers a request with a 401 challenge, then verifies the authenticated request and signs the response.
+    async fn serve_long_term_credentials(server: &UdpSocket) -> Message {
+        let (request, source) = recv_message(server).await;
+        let challenge = challenge(&request, unauthorized(), "nonce", None);
+        server.send_to(&challenge.to_raw(), source).await.unwrap();
+
+        let (request, source) = recv_message(server).await;
+        let key = PasswordAlgorithm::MD5
+            .derive_key("user", "realm", "password")
+            .unwrap();
+        request.verify_integrity(&key).unwrap();
+        let response = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+            .transaction_id(request.get_transaction_id())
+            .attr(AttributeValue::XORMappedAddress(source))
+            .message_integrity(&key)
+            .build()
+            .unwrap();
+        server.send_to(&response.to_raw(), source).await.unwrap();
+        request
+    }
+
+    #[test]
+    fn credentials_are_kept_for_each_server() {
+        task::block_on(async {
+            let server_a = bind().await;
+            let server_a_addr = server_a.local_addr().unwrap();
+            let server_b = bind().await;
+            let server_b_addr = server_b.local_addr().unwrap();
+            let opts = Options {
+                credentials: Some(credentials()),
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+
+            let responder = task::spawn(async move {
+                let request = serve_long_term_credentials(&server_a).await;
+                assert_eq!(Attribute::get_realm(&request), Some(String::from("realm")));
+                server_a
+            });
+            let res = client.binding_request(server_a_addr, None).await.unwrap();
+            assert_eq!(res.message.get_class(), Class::SuccessResponse);
+            let server_a = responder.await;
+
+            // Server B never challenged the client, so neither the request nor the response is authenticated.
+            let responder = task::spawn(async move {
+                let (request, source) = recv_message(&server_b).await;
+                let response = success_response(&request, source);
+                server_b.send_to(&response.to_raw(), source).await.unwrap();
+                request
+            });
+            let res = client.binding_request(server_b_addr, None).await.unwrap();
+            assert_eq!(res.message.get_class(), Class::SuccessResponse);
+            let request = responder.await;
+            for attr in [
+                Attribute::Username,
+                Attribute::Realm,
+                Attribute::Nonce,
+                Attribute::MessageIntegrity,
+            ] {
+                assert_eq!(request.get_raw_attr_value(attr), None);
+            }
+
+            // Server A still gets the credentials and must still sign its responses.
+            let responder = task::spawn(async move {
+                let (request, source) = recv_message(&server_a).await;
+                let response = success_response(&request, source);
+                server_a.send_to(&response.to_raw(), source).await.unwrap();
+                request
+            });
+            let res = client.binding_request(server_a_addr, None).await;
+            let request = responder.await;
+            assert_eq!(Attribute::get_nonce(&request), Some(String::from("nonce")));
+            assert!(matches!(res, Err(STUNClientError::MissingIntegrityError())));
+        });
+    }
+
+    #[test]
+    fn stale_nonces_are_replaced() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let key = PasswordAlgorithm::MD5
+                .derive_key("user", "realm", "password")
+                .unwrap();
+            let responder = task::spawn(async move {
+                let (request, source) = recv_message(&server).await;
+                let response = challenge(&request, unauthorized(), "nonce-1", None);
+                server.send_to(&response.to_raw(), source).await.unwrap();
+
+                let (request, source) = recv_message(&server).await;
+                assert_eq!(
+                    Attribute::get_nonce(&request),
+                    Some(String::from("nonce-1"))
+                );
+                let stale_nonce = ErrorCode::StaleNonce(String::from("Stale Nonce"));
+                let response = challenge(&request, stale_nonce, "nonce-2", None);
+                server.send_to(&response.to_raw(), source).await.unwrap();
+
+                let (request, source) = recv_message(&server).await;
+                request.verify_integrity(&key).unwrap();
+                let response = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+                    .transaction_id(request.get_transaction_id())
+                    .attr(AttributeValue::XORMappedAddress(source))
+                    .message_integrity(&key)
+                    .build()
+                    .unwrap();
+                server.send_to(&response.to_raw(), source).await.unwrap();
+                (request, drain(&server).await.len())
+            });
+
+            let opts = Options {
+                credentials: Some(credentials()),
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_eq!(res.message.get_class(), Class::SuccessResponse);
+            let (request, later) = responder.await;
+            assert_eq!(
+                Attribute::get_nonce(&request),
+                Some(String::from("nonce-2"))
+            );
+            assert_eq!(later, 0);
+        });
+    }
+
+    #[test]
+    fn sha256_is_negotiated_with_the_nonce_cookie() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            // The "Password algorithms" bit of the security feature set follows the nonce cookie.
+            let nonce = format!("{}gAAAnonce", NONCE_COOKIE);
+            let offered = vec![PasswordAlgorithm::MD5, PasswordAlgorithm::SHA256];
+            let key = PasswordAlgorithm::SHA256
+                .derive_key("user", "realm", "password")
+                .unwrap();
+            let responder = task::spawn(async move {
+                let (request, source) = recv_message(&server).await;
+                let response = challenge(&request, unauthorized(), &nonce, Some(offered));
+                server.send_to(&response.to_raw(), source).await.unwrap();
+
+                let (request, source) = recv_message(&server).await;
+                request.verify_integrity(&key).unwrap();
+                let response = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+                    .transaction_id(request.get_transaction_id())
+                    .attr(AttributeValue::XORMappedAddress(source))
+                    .message_integrity_sha256(&key, MESSAGE_INTEGRITY_SHA256_BYTE_SIZE)
+                    .build()
+                    .unwrap();
+                server.send_to(&response.to_raw(), source).await.unwrap();
+                (request, response)
+            });
+
+            let opts = Options {
+                credentials: Some(credentials()),
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            let (request, response) = responder.await;
+            assert_eq!(res.message, response);
+            assert_eq!(
+                Attribute::get_password_algorithm(&request),
+                Some(PasswordAlgorithm::SHA256)
+            );
+            assert_eq!(
+                Attribute::get_password_algorithms(&request),
+                Some(vec![PasswordAlgorithm::MD5, PasswordAlgorithm::SHA256])
+            );
+            assert!(request
+                .get_raw_attr_value(Attribute::MessageIntegritySHA256)
+                .is_some());
+            assert_eq!(
+                request.get_raw_attr_value(Attribute::MessageIntegrity),
+                None
+            );
+
+            // Without PASSWORD-ALGORITHMS the algorithm may have been downgraded by an attacker.
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            serve(server, |request, _| {
+                let nonce = format!("{}gAAAnonce", NONCE_COOKIE);
+                Some(challenge(request, unauthorized(), &nonce, None))
+            });
+            let res = client.binding_request(server_addr, None).await;
+            assert!(matches!(res, Err(STUNClientError::AuthenticationError(_))));
+        });
+    }
+
+    #[test]
+    fn userhash_is_sent_when_the_server_requests_username_anonymity() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let key = PasswordAlgorithm::MD5
+                .derive_key("user", "realm", "password")
+                .unwrap();
+            let responder = task::spawn(async move {
+                let (request, source) = recv_message(&server).await;
+                // The "Username anonymity" bit of the security feature set follows the nonce cookie.
+                let nonce = format!("{}QAAAnonce", NONCE_COOKIE);
+                let response = challenge(&request, unauthorized(), &nonce, None);
+                server.send_to(&response.to_raw(), source).await.unwrap();
+
+                let (request, source) = recv_message(&server).await;
+                request.verify_integrity(&key).unwrap();
+                let response = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+                    .transaction_id(request.get_transaction_id())
+                    .attr(AttributeValue::XORMappedAddress(source))
+                    .message_integrity(&key)
+                    .build()
+                    .unwrap();
+                server.send_to(&response.to_raw(), source).await.unwrap();
+                request
+            });
+
+            let opts = Options {
+                credentials: Some(credentials()),
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_eq!(res.message.get_class(), Class::SuccessResponse);
+            let request = responder.await;
+            assert_eq!(
+                Attribute::get_userhash(&request),
+                Some(Attribute::generate_userhash_value("user", "realm").unwrap())
+            );
+            assert_eq!(Attribute::get_username(&request), None);
+        });
+    }
+
+    #[test]
+    fn a_second_401_is_returned_without_retrying() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let responder = task::spawn(async move {
+                for _ in 0..2 {
+                    let (request, source) = recv_message(&server).await;
+                    let response = challenge(&request, unauthorized(), "nonce", None);
+                    server.send_to(&response.to_raw(), source).await.unwrap();
+                }
+                drain(&server).await.len()
+            });
+
+            let opts = Options {
+                credentials: Some(credentials()),
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert!(matches!(
+                Attribute::get_error_code(&res.message),
+                Some(ErrorCode::Unauthorized(_))
+            ));
+            assert_eq!(responder.await, 0);
+        });
+    }
+
+    #[test]
+    fn long_nonces_and_realms_are_echoed_as_received() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            // The nonce exceeds the limit of values sent by the client, and SASLprep would map the no-break space.
+            let nonce = vec![b'n'; 600];
+            let realm = "example\u{a0}realm".as_bytes().to_vec();
+            let (challenge_nonce, challenge_realm) = (nonce.clone(), realm.clone());
+            let responder = task::spawn(async move {
+                let (request, source) = recv_message(&server).await;
+                let challenge = MessageBuilder::new(Method::Binding, Class::ErrorResponse)
+                    .transaction_id(request.get_transaction_id())
+                    .attr(AttributeValue::ErrorCode {
+                        code: 401,
+                        reason: String::from("Unauthorized"),
+                    })
+                    .attr(AttributeValue::Opaque(Attribute::Realm, challenge_realm))
+                    .attr(AttributeValue::Opaque(Attribute::Nonce, challenge_nonce))
+                    .build()
+                    .unwrap();
+                server.send_to(&challenge.to_raw(), source).await.unwrap();
+
+                let (request, source) = recv_message(&server).await;
+                let key = PasswordAlgorithm::MD5
+                    .derive_key("user", "example realm", "password")
+                    .unwrap();
+                request.verify_integrity(&key).unwrap();
+                let response = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+                    .transaction_id(request.get_transaction_id())
+                    .attr(AttributeValue::XORMappedAddress(source))
+                    .message_integrity(&key)
+                    .build()
+                    .unwrap();
+                server.send_to(&response.to_raw(), source).await.unwrap();
+                request
+            });
+
+            let opts = Options {
+                credentials: Some(credentials()),
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_eq!(res.message.get_class(), Class::SuccessResponse);
+            let request = responder.await;
+            assert_eq!(request.get_raw_attr_value(Attribute::Nonce), Some(nonce));
+            assert_eq!(request.get_raw_attr_value(Attribute::Realm), Some(realm));
+
+            // Values supplied by the caller are still limited.
+            let res = client
+                .binding_request(
+                    server_addr,
+                    Some(vec![AttributeValue::Nonce("n".repeat(MAX_NONCE_BYTE_SIZE))]),
+                )
+                .await;
+            assert!(matches!(
+                res,
+                Err(STUNClientError::InvalidAttributeLengthError(
+                    Attribute::Nonce,
+                    MAX_NONCE_BYTE_SIZE
+                ))
+            ));
+        });
+    }
+
+    // A classic RFC3489 response, whose SOURCE-ADDRESS and CHANGED-ADDRESS are comprehension-required.
+    fn rfc3489_response(request: &Message, mapped_addr: SocketAddr) -> Vec<u8> {
+        let server_addr: SocketAddr = "192.0.2.1:3478".parse().unwrap();
+        let changed_addr: SocketAddr = "192.0.2.2:3479".parse().unwrap();
+        MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+            .transaction_id(request.get_transaction_id())
+            .attr(AttributeValue::MappedAddress(mapped_addr))
+            .attr(AttributeValue::Opaque(
+                Attribute::from_u16(ATTR_SOURCE_ADDRESS),
+                Attribute::generate_mapped_address_value(&server_addr),
+            ))
+            .attr(AttributeValue::Opaque(
+                Attribute::from_u16(ATTR_CHANGED_ADDRESS),
+                Attribute::generate_mapped_address_value(&changed_addr),
+            ))
+            .build()
+            .unwrap()
+            .to_raw()
+    }
+
+    #[test]
+    fn rfc3489_responses_are_accepted_in_compatible_mode() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let opts = Options {
+                rfc3489_compatible: true,
+                ..options()
+            };
+            let mut rfc3489_client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+
+            let responder = task::spawn(async move {
+                let (request, source) = recv_message(&server).await;
+                let response = rfc3489_response(&request, source);
+                server.send_to(&response, source).await.unwrap();
+
+                // RFC3489 servers use the magic cookie bytes as part of the transaction ID.
+                let (request, source) = recv_message(&server).await;
+                let mut response = rfc3489_response(&request, source);
+                response[4..8].copy_from_slice(&[1, 2, 3, 4]);
+                server.send_to(&response, source).await.unwrap();
+            });
+
+            let unknown = vec![
+                Attribute::from_u16(ATTR_SOURCE_ADDRESS),
+                Attribute::from_u16(ATTR_CHANGED_ADDRESS),
+            ];
+            let res = client.binding_request(server_addr, None).await;
+            assert!(matches!(
+                res,
+                Err(STUNClientError::UnknownAttributesError(attrs)) if attrs == unknown
+            ));
+
+            let res = rfc3489_client
+                .binding_request(server_addr, None)
+                .await
+                .unwrap();
+            responder.await;
+            assert_eq!(res.message.get_class(), Class::SuccessResponse);
+            assert_eq!(Attribute::get_mapped_address(&res.message), Some(res.local));
+        });
+    }
+
+    fn try_alternate(request: &Message, alternate: SocketAddr, domain: Option<&str>) -> Message {
+        let mut builder = MessageBuilder::new(Method::Binding, Class::ErrorResponse)
+            .transaction_id(request.get_transaction_id())
+            .attr(AttributeValue::ErrorCode {
+                code: 300,
+                reason: String::from("Try Alternate"),
+            })
+            .attr(AttributeValue::AlternateServer(alternate));
+        if let Some(domain) = domain {
+            builder = builder.attr(AttributeValue::AlternateDomain(String::from(domain)));
+        }
+        builder.build().unwrap()
+    }
+
+    // Binds servers that redirect to the next one, and a last server that answers.
+    async fn redirect_chain(redirects: usize) -> Vec<SocketAddr> {
+        let mut sockets = vec![];
+        for _ in 0..=redirects {
+            sockets.push(bind().await);
+        }
+        let addrs: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+        for (i, socket) in sockets.into_iter().enumerate() {
+            match addrs.get(i + 1) {
+                Some(next) => {
+                    let next = *next;
+                    serve(socket, move |request, _| {
+                        Some(try_alternate(request, next, Some("stun.example.org")))
+                    })
+                }
+                None => serve(socket, |request, source| {
+                    Some(success_response(request, source))
+                }),
+            }
+        }
+        addrs
+    }
+
+    fn redirect_options(max_redirects: u32) -> Options {
+        Options {
+            max_redirects,
+            ..options()
+        }
+    }
+
+    #[test]
+    fn try_alternate_responses_are_followed() {
+        task::block_on(async {
+            let servers = redirect_chain(2).await;
+
+            let mut client = Client::new("127.0.0.1:0", Some(redirect_options(2)))
+                .await
+                .unwrap();
+            let res = client.binding_request(servers[0], None).await.unwrap();
+            assert_eq!(res.message.get_class(), Class::SuccessResponse);
+            assert_eq!(res.server, servers[2]);
+            assert_eq!(res.alternate_domain, Some(String::from("stun.example.org")));
+
+            // Redirects are not followed by default.
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let res = client.binding_request(servers[0], None).await.unwrap();
+            assert!(matches!(
+                Attribute::get_error_code(&res.message),
+                Some(ErrorCode::TryAlternate(_))
+            ));
+            assert_eq!(res.server, servers[0]);
+            assert_eq!(res.alternate_domain, None);
+
+            let mut client = Client::new("127.0.0.1:0", Some(redirect_options(1)))
+                .await
+                .unwrap();
+            let res = client.binding_request(servers[0], None).await;
+            assert!(matches!(res, Err(STUNClientError::RedirectError(_))));
+        });
+    }
+
+    #[test]
+    fn redirect_loops_and_other_address_families_are_rejected() {
+        task::block_on(async {
+            let server_a = bind().await;
+            let server_a_addr = server_a.local_addr().unwrap();
+            let server_b = bind().await;
+            let server_b_addr = server_b.local_addr().unwrap();
+            serve(server_a, move |request, _| {
+                Some(try_alternate(request, server_b_addr, None))
+            });
+            serve(server_b, move |request, _| {
+                Some(try_alternate(request, server_a_addr, None))
+            });
+            let mut client = Client::new("127.0.0.1:0", Some(redirect_options(5)))
+                .await
+                .unwrap();
+            let res = client.binding_request(server_a_addr, None).await;
+            assert!(matches!(res, Err(STUNClientError::RedirectError(_))));
+
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            serve(server, |request, _| {
+                Some(try_alternate(request, "[::1]:3478".parse().unwrap(), None))
+            });
+            let res = client.binding_request(server_addr, None).await;
+            assert!(matches!(res, Err(STUNClientError::RedirectError(_))));
+        });
+    }
+
+    #[test]
+    fn responses_from_unexpected_sources_are_dropped() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let spoofer = bind().await;
+            let spoofed_addr: SocketAddr = "192.0.2.99:1".parse().unwrap();
+            let responder = task::spawn(async move {
+                for _ in 0..2 {
+                    let (request, source) = recv_message(&server).await;
+                    let spoofed = success_response(&request, spoofed_addr);
+                    spoofer.send_to(&spoofed.to_raw(), source).await.unwrap();
+                    task::sleep(Duration::from_millis(50)).await;
+                    let response = success_response(&request, source);
+                    server.send_to(&response.to_raw(), source).await.unwrap();
+                }
+            });
+
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_eq!(res.source, server_addr);
+            assert_eq!(
+                Attribute::get_xor_mapped_address(&res.message),
+                Some(res.local)
+            );
+
+            // Without validation the spoofed response arrives first and is accepted.
+            let opts = Options {
+                validate_source: false,
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_ne!(res.source, server_addr);
+            assert_eq!(
+                Attribute::get_xor_mapped_address(&res.message),
+                Some(spoofed_addr)
+            );
+            responder.await;
+        });
+    }
+
+    #[test]
+    fn change_request_responses_are_accepted_from_other_address() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let other = bind().await;
+            let other_addr = other.local_addr().unwrap();
+            let spoofer = bind().await;
+
+            // The first CHANGE-REQUEST is answered from OTHER-ADDRESS, later ones from an unrelated address.
+            task::spawn(async move {
+                let mut change_requests = 0;
+                loop {
+                    let (request, source) = recv_message(&server).await;
+                    let response = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+                        .transaction_id(request.get_transaction_id())
+                        .attr(AttributeValue::XORMappedAddress(source))
+                        .attr(AttributeValue::OtherAddress(other_addr))
+                        .build()
+                        .unwrap()
+                        .to_raw();
+                    if request
+                        .get_raw_attr_value(Attribute::ChangeRequest)
+                        .is_none()
+                    {
+                        server.send_to(&response, source).await.unwrap();
+                        continue;
+                    }
+                    change_requests += 1;
+                    let socket = if change_requests == 1 {
+                        &other
+                    } else {
+                        &spoofer
+                    };
+                    socket.send_to(&response, source).await.unwrap();
+                }
+            });
+
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_eq!(Attribute::get_other_address(&res.message), Some(other_addr));
+
+            let change_request = || {
+                Some(vec![AttributeValue::ChangeRequest {
+                    change_ip: true,
+                    change_port: true,
+                }])
+            };
+            let res = client
+                .binding_request(server_addr, change_request())
+                .await
+                .unwrap();
+            assert_eq!(res.server, server_addr);
+            assert_eq!(res.source, other_addr);
+
+            let res = client.binding_request(server_addr, change_request()).await;
+            assert!(matches!(res, Err(STUNClientError::TimeoutError())));
+        });
+    }
+
+    #[test]
+    fn responses_report_retransmissions_and_rtt() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let responder = task::spawn(async move {
+                // The first request is dropped.
+                recv_message(&server).await;
+                let (request, source) = recv_message(&server).await;
+                let response = success_response(&request, source);
+                server.send_to(&response.to_raw(), source).await.unwrap();
+            });
+
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            responder.await;
+            assert_eq!(res.retransmissions, 1);
+            // The RTT is measured from the retransmission, not from the dropped request.
+            assert!(res.rtt < Duration::from_millis(TEST_RTO_MS));
+            assert_eq!(res.local, client.socket.local_addr().unwrap());
+
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            serve(server, |request, source| {
+                Some(success_response(request, source))
+            });
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_eq!(res.retransmissions, 0);
+        });
+    }
+
+    #[test]
+    fn non_stun_datagrams_are_demultiplexed_during_transactions() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            // A DTLS record larger than the old default buffer, sent before the response.
+            let mut dtls = vec![0x17; 1400];
+            dtls[1..3].copy_from_slice(&[0xfe, 0xfd]);
+            let datagram = dtls.clone();
+            serve(server, move |request, source| {
+                let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.connect(source).unwrap();
+                socket.send(&datagram).unwrap();
+                Some(success_response(request, source))
+            });
+
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let mut non_stun = client.non_stun_receiver().unwrap();
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            assert_eq!(res.retransmissions, 0);
+            let (received, _) = non_stun.next().await.unwrap();
+            assert_eq!(received, dtls);
+
+            // Datagrams larger than the buffer are dropped instead of being forwarded truncated.
+            let opts = Options {
+                recv_buf_size: 1000,
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+            let client_addr = client.socket.local_addr().unwrap();
+            let mut non_stun = client.non_stun_receiver().unwrap();
+            let peer = bind().await;
+            peer.send_to(&dtls, client_addr).await.unwrap();
+            peer.send_to(&dtls[..1000], client_addr).await.unwrap();
+            let (received, _) = non_stun.next().await.unwrap();
+            assert_eq!(received.len(), 1000);
+        });
+    }
+
+    #[test]
+    fn keepalive_indications_are_sent_every_interval_until_stopped() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+
+            let handle = client
+                .start_keepalive(
+                    server_addr,
+                    Duration::from_millis(50),
+                    Keepalive::Indication,
+                )
+                .await
+                .unwrap();
+            // Indications are sent at 0, 50, ..., 250 ms.
+            let mut indications = 0;
+            let deadline = Instant::now() + Duration::from_millis(275);
+            while let Ok((msg, _)) = future::timeout(
+                deadline.saturating_duration_since(Instant::now()),
+                recv_message(&server),
+            )
+            .await
+            {
+                assert_eq!(msg.get_method(), Method::Binding);
+                assert_eq!(msg.get_class(), Class::Indication);
+                indications += 1;
+            }
+            assert!((4..=7).contains(&indications), "{}", indications);
+
+            handle.stop();
+            // An indication may have been sent while stopping.
+            assert!(drain(&server).await.len() <= 1);
+            let res = future::timeout(Duration::from_millis(200), recv_message(&server)).await;
+            assert!(res.is_err());
+        });
+    }
+
+    #[test]
+    fn keepalive_requests_report_responses() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            serve(server, |request, source| {
+                Some(success_response(request, source))
+            });
+            let client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+
+            let mut handle = client
+                .start_keepalive(server_addr, Duration::from_millis(50), Keepalive::Request)
+                .await
+                .unwrap();
+            let mut responses = handle.responses().unwrap();
+            assert!(handle.responses().is_none());
+            for _ in 0..2 {
+                let res = responses.next().await.unwrap().unwrap();
+                assert_eq!(
+                    Attribute::get_xor_mapped_address(&res.message),
+                    Some(res.local)
+                );
+            }
+            handle.stop();
+        });
+    }
+
+    #[test]
+    fn inbound_binding_requests_are_answered_and_reported() {
+        task::block_on(async {
+            let peer = bind().await;
+            let peer_addr = peer.local_addr().unwrap();
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let client_addr = client.socket.local_addr().unwrap();
+            let mut requests = client.request_receiver().unwrap();
+            assert!(client.request_receiver().is_none());
+
+            let request = MessageBuilder::new(Method::Binding, Class::Request)
+                .fingerprint()
+                .build()
+                .unwrap();
+            peer.send_to(&request.to_raw(), client_addr).await.unwrap();
+            let (response, source) = recv_message(&peer).await;
+            assert_eq!(source, client_addr);
+            assert_eq!(response.get_class(), Class::SuccessResponse);
+            assert_eq!(response.get_transaction_id(), request.get_transaction_id());
+            assert_eq!(
+                Attribute::get_xor_mapped_address(&response),
+                Some(peer_addr)
+            );
+
+            let event = requests.next().await.unwrap();
+            assert_eq!(event.source, peer_addr);
+            assert_eq!(
+                event.request.get_transaction_id(),
+                request.get_transaction_id()
+            );
+            assert_eq!(event.response, Some(response));
+        });
+    }
+
+    #[test]
+    fn colliding_transaction_ids_are_regenerated() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            serve(server, |request, source| {
+                Some(success_response(request, source))
+            });
+            let client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+            let outstanding = TransactionId::random();
+            let (tx, _rx) = mpsc::channel(1);
+            client.transactions.lock().unwrap().insert(
+                outstanding,
+                Transaction {
+                    source_ip: None,
+                    source_port: None,
+                    tx,
+                },
+            );
+            let request = |id: TransactionId| {
+                MessageBuilder::new(Method::Binding, Class::Request)
+                    .transaction_id(id)
+                    .build()
+            };
+
+            // The first request collides with the outstanding transaction and is built again.
+            let attempts = Cell::new(0);
+            let res = client
+                .transport()
+                .send_request(server_addr, None, None, || {
+                    attempts.set(attempts.get() + 1);
+                    match attempts.get() {
+                        1 => request(outstanding),
+                        _ => request(TransactionId::random()),
+                    }
+                })
+                .await
+                .unwrap();
+            assert_eq!(attempts.get(), 2);
+            assert_ne!(res.message.get_transaction_id(), outstanding);
+            assert!(client
+                .transactions
+                .lock()
+                .unwrap()
+                .contains_key(&outstanding));
+
+            let attempts = Cell::new(0);
+            let res = client
+                .transport()
+                .send_request(server_addr, None, None, || {
+                    attempts.set(attempts.get() + 1);
+                    request(outstanding)
+                })
+                .await;
+            assert!(matches!(res, Err(STUNClientError::Unknown(_))));
+            assert_eq!(attempts.get(), MAX_TRANSACTION_ID_ATTEMPTS);
+            assert_eq!(client.transactions.lock().unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn requests_are_retransmitted_with_doubling_rto() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+
+            // The server drops the first 2 requests and answers the third.
+            let responder = task::spawn(async move {
+                let mut arrivals = vec![];
+                loop {
+                    let (request, source) = recv_message(&server).await;
+                    arrivals.push(Instant::now());
+                    if arrivals.len() == 3 {
+                        let response = success_response(&request, source);
+                        server.send_to(&response.to_raw(), source).await.unwrap();
+                        return (arrivals, drain(&server).await.len());
+                    }
+                }
+            });
+            let res = client.binding_request(server_addr, None).await.unwrap();
+            let (arrivals, later) = responder.await;
+            assert_eq!(later, 0);
+            assert_eq!(
+                Attribute::get_xor_mapped_address(&res.message),
+                Some(res.local)
+            );
+
+            // The arrivals are delayed by the scheduling of the server task, so the intervals are only approximate.
+            let rto = Duration::from_millis(TEST_RTO_MS);
+            let first = arrivals[1] - arrivals[0];
+            let second = arrivals[2] - arrivals[1];
+            assert!(first >= rto * 3 / 4 && first < rto * 2, "{:?}", first);
+            assert!(second >= rto * 3 / 2 && second < rto * 4, "{:?}", second);
+        });
+    }
+
+    #[test]
+    fn transaction_fails_rm_times_rto_after_the_last_request() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let mut client = Client::new("127.0.0.1:0", Some(options())).await.unwrap();
+
+            let started = Instant::now();
+            let res = client.binding_request(server_addr, None).await;
+            let elapsed = started.elapsed();
+            assert!(matches!(res, Err(STUNClientError::TimeoutError())));
+            // 100 + 200 ms between the requests and 4 * 100 ms after the last one,
+            // well before recv_timeout_ms.
+            let expected = Duration::from_millis(TEST_RTO_MS * 7);
+            assert!(
+                elapsed >= expected && elapsed < expected * 2,
+                "{:?}",
+                elapsed
+            );
+
+            let requests = drain(&server).await;
+            assert_eq!(requests.len(), 3);
+            assert!(requests
+                .iter()
+                .all(|r| r.get_transaction_id() == requests[0].get_transaction_id()));
+        });
+    }
+
+    #[test]
+    fn default_options_keep_the_3_second_timeout() {
+        let opts = Options::default();
+        assert_eq!(opts.recv_timeout_ms, 3000);
+        // The RTO doubles between the Rc requests, and Rm times the RTO is waited after the last one.
+        let schedule = opts.rto_ms * ((1 << (opts.rc - 1)) - 1) + opts.rto_ms * opts.rm as u64;
+        assert_eq!(schedule, RFC8489_TRANSACTION_TIMEOUT_MS);
+    }
+
+    #[test]
+    fn recv_timeout_caps_the_transaction() {
+        task::block_on(async {
+            let server = bind().await;
+            let server_addr = server.local_addr().unwrap();
+            let opts = Options {
+                recv_timeout_ms: 250,
+                ..options()
+            };
+            let mut client = Client::new("127.0.0.1:0", Some(opts)).await.unwrap();
+
+            let started = Instant::now();
+            let res = client.binding_request(server_addr, None).await;
+            assert!(matches!(res, Err(STUNClientError::TimeoutError())));
+            assert!(started.elapsed() < Duration::from_millis(500));
+            assert_eq!(drain(&server).await.len(), 2);
+        });
+    }
 }
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use super::message::Attribute;
+
 /// Defines the error used by the stun_client library.
 #[derive(Error, Debug)]
 pub enum STUNClientError {
@@ -11,6 +13,34 @@ pub enum STUNClientError {
     NotSupportedError(String),
     #[error("request timeout")]
     TimeoutError(),
+    #[error("MESSAGE-INTEGRITY mismatch")]
+    IntegrityMismatchError(),
+    #[error("MESSAGE-INTEGRITY is missing")]
+    MissingIntegrityError(),
+    #[error("FINGERPRINT mismatch")]
+    FingerprintMismatchError(),
+    #[error("authentication failed: {0}")]
+    AuthenticationError(String),
+    #[error("invalid length {1} of attribute {0:?}")]
+    InvalidAttributeLengthError(Attribute, usize),
+    #[error("invalid value of attribute {0:?}: {1}")]
+    InvalidAttributeValueError(Attribute, String),
+    #[error("invalid magic cookie: {0:#010x}")]
+    MagicCookieMismatchError(u32),
+    #[error("the most significant 2 bits of the message type must be zero: {0:#06x}")]
+    InvalidMessageTypeError(u16),
+    #[error("message length {0} does not match the {1} bytes following the header")]
+    LengthMismatchError(u16, usize),
+    #[error("message length {0} is not a multiple of 4")]
+    UnalignedLengthError(u16),
+    #[error("unknown comprehension-required attributes: {0:?}")]
+    UnknownAttributesError(Vec<Attribute>),
+    #[error("redirect failed: {0}")]
+    RedirectError(String),
+    #[error("buffer too small: {0} bytes required, {1} bytes available")]
+    BufferTooSmallError(usize, usize),
+    #[error("message length {0} does not fit in the 16-bit length field")]
+    MessageTooLargeError(usize),
     #[error("unknown error: {0}")]
     Unknown(String),
 }
@@ -22,6 +52,30 @@ impl Clone for STUNClientError {
             Self::IOError(e) => Self::IOError(std::io::Error::new(e.kind(), e.to_string())),
             Self::NotSupportedError(msg) => Self::NotSupportedError(msg.clone()),
             Self::TimeoutError() => Self::TimeoutError(),
+            Self::IntegrityMismatchError() => Self::IntegrityMismatchError(),
+            Self::MissingIntegrityError() => Self::MissingIntegrityError(),
+            Self::FingerprintMismatchError() => Self::FingerprintMismatchError(),
+            Self::AuthenticationError(msg) => Self::AuthenticationError(msg.clone()),
+            Self::InvalidAttributeLengthError(attr, length) => {
+                Self::InvalidAttributeLengthError(*attr, *length)
+            }
+            Self::InvalidAttributeValueError(attr, msg) => {
+                Self::InvalidAttributeValueError(*attr, msg.clone())
+            }
+            Self::MagicCookieMismatchError(cookie) => Self::MagicCookieMismatchError(*cookie),
+            Self::InvalidMessageTypeError(message_type) => {
+                Self::InvalidMessageTypeError(*message_type)
+            }
+            Self::LengthMismatchError(length, actual) => {
+                Self::LengthMismatchError(*length, *actual)
+            }
+            Self::UnalignedLengthError(length) => Self::UnalignedLengthError(*length),
+            Self::UnknownAttributesError(attrs) => Self::UnknownAttributesError(attrs.clone()),
+            Self::RedirectError(msg) => Self::RedirectError(msg.clone()),
+            Self::BufferTooSmallError(required, available) => {
+                Self::BufferTooSmallError(*required, *available)
+            }
+            Self::MessageTooLargeError(length) => Self::MessageTooLargeError(*length),
             Self::Unknown(msg) => Self::Unknown(msg.clone()),
         }
     }
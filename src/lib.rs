@@ -14,7 +14,8 @@
 //!     let res = client
 //!         .binding_request("stun.l.google.com:19302", None)
 //!         .await
-//!         .unwrap();
+//!         .unwrap()
+//!         .message;
 //!     let class = res.get_class();
 //!     match class {
 //!         Class::SuccessResponse => {
@@ -28,9 +29,12 @@
 
 mod client;
 mod error;
+pub mod mapped_address_monitor;
 mod message;
 pub mod nat_behavior_discovery;
+mod request_handler;
 
 pub use client::*;
 pub use error::*;
 pub use message::*;
+pub use request_handler::*;
@@ -0,0 +1,298 @@
+//! This module monitors the mapped address of a Client, which NATs such as CGNAT may change silently.
+//! The mapped address is refreshed periodically with Binding requests, and its changes are delivered as a Stream.
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+
+use async_macros::select;
+use async_std::net::SocketAddr;
+use async_std::task::{self, JoinHandle};
+use futures::channel::mpsc;
+use futures::stream::{Stream, StreamExt};
+use futures::task::{Context, Poll};
+use futures::SinkExt;
+use rand::Rng;
+
+use super::client::*;
+use super::message::*;
+
+const DEFAULT_INTERVAL_MS: u64 = 30000;
+const DEFAULT_JITTER_MS: u64 = 5000;
+const DEFAULT_MAX_BACKOFF_MS: u64 = 300000;
+const CHANGE_CHANNEL_SIZE: usize = 16;
+
+/// Mapped address monitor options.
+///
+/// The mapped address is refreshed every `interval_ms` plus a random delay of up to `jitter_ms`,
+/// so that many agents started together do not refresh at the same time.
+/// While no server answers, the interval doubles after each refresh up to `max_backoff_ms`.
+#[derive(Clone, Debug)]
+pub struct MonitorOptions {
+    pub interval_ms: u64,
+    pub jitter_ms: u64,
+    pub max_backoff_ms: u64,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> MonitorOptions {
+        MonitorOptions {
+            interval_ms: DEFAULT_INTERVAL_MS,
+            jitter_ms: DEFAULT_JITTER_MS,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF_MS,
+        }
+    }
+}
+
+/// A change of the mapped address seen by a server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MappedAddressChange {
+    /// The previous mapped address, None when it is discovered for the first time.
+    pub old_addr: Option<SocketAddr>,
+    pub new_addr: SocketAddr,
+    pub server: SocketAddr,
+    pub time: SystemTime,
+}
+
+/// Monitors the mapped address of a Client against one or more servers.
+/// Each server is tracked separately, because NATs without endpoint-independent mapping
+/// map the socket to a different address for each server.
+///
+/// The monitor is a Stream of [`MappedAddressChange`], and the first item of each server reports its initial mapped address.
+/// The monitor owns the Client while it runs, and [`MappedAddressMonitor::stop`] gives it back.
+pub struct MappedAddressMonitor {
+    changes: mpsc::Receiver<MappedAddressChange>,
+    stop_tx: mpsc::Sender<bool>,
+    handle: JoinHandle<Client>,
+}
+
+impl MappedAddressMonitor {
+    /// Starts refreshing the mapped address with Binding requests to the servers.
+    pub fn start(
+        client: Client,
+        servers: Vec<SocketAddr>,
+        opts: Option<MonitorOptions>,
+    ) -> MappedAddressMonitor {
+        let opts = opts.unwrap_or_default();
+        let (changes_tx, changes_rx) = mpsc::channel(CHANGE_CHANNEL_SIZE);
+        let (stop_tx, stop_rx) = mpsc::channel(1);
+        let handle =
+            task::spawn(async move { Self::run(client, servers, opts, stop_rx, changes_tx).await });
+        MappedAddressMonitor {
+            changes: changes_rx,
+            stop_tx,
+            handle,
+        }
+    }
+
+    /// Stops the monitor and returns the Client. A refresh in progress is completed first.
+    pub async fn stop(self) -> Client {
+        let MappedAddressMonitor {
+            changes,
+            mut stop_tx,
+            handle,
+        } = self;
+        // Closing the stream also ends a delivery waiting for a slow consumer.
+        drop(changes);
+        stop_tx.try_send(true).ok();
+        handle.await
+    }
+
+    async fn run(
+        mut client: Client,
+        servers: Vec<SocketAddr>,
+        opts: MonitorOptions,
+        mut stop_rx: mpsc::Receiver<bool>,
+        mut changes_tx: mpsc::Sender<MappedAddressChange>,
+    ) -> Client {
+        let mut mapped_addrs: Vec<Option<SocketAddr>> = vec![None; servers.len()];
+        let mut interval_ms = opts.interval_ms;
+        loop {
+            let mut refreshed = false;
+            for (server, mapped_addr) in servers.iter().zip(mapped_addrs.iter_mut()) {
+                let new_addr = match Self::refresh(&mut client, *server).await {
+                    Some(new_addr) => new_addr,
+                    None => continue,
+                };
+                refreshed = true;
+                if *mapped_addr != Some(new_addr) {
+                    let change = MappedAddressChange {
+                        old_addr: *mapped_addr,
+                        new_addr,
+                        server: *server,
+                        time: SystemTime::now(),
+                    };
+                    // Changes are delivered in order, so a slow consumer delays the next refresh.
+                    if changes_tx.send(change).await.is_err() {
+                        return client;
+                    }
+                    *mapped_addr = Some(new_addr);
+                }
+            }
+
+            interval_ms = if refreshed {
+                opts.interval_ms
+            } else {
+                (interval_ms * 2).min(opts.max_backoff_ms.max(opts.interval_ms))
+            };
+            let jitter_ms = if opts.jitter_ms > 0 {
+                rand::thread_rng().gen_range(0..=opts.jitter_ms)
+            } else {
+                0
+            };
+
+            // The channel is closed when the monitor is dropped.
+            let sleep = async {
+                task::sleep(Duration::from_millis(interval_ms + jitter_ms)).await;
+                false
+            };
+            let stop = async {
+                stop_rx.next().await;
+                true
+            };
+            if select!(sleep, stop).await {
+                return client;
+            }
+        }
+    }
+
+    // Returns the mapped address seen by the server, or None if the request failed.
+    async fn refresh(client: &mut Client, server: SocketAddr) -> Option<SocketAddr> {
+        let res = client.binding_request(server, None).await.ok()?.message;
+        if res.get_class() != Class::SuccessResponse {
+            return None;
+        }
+        // RFC3489 servers only send MAPPED-ADDRESS.
+        Attribute::get_xor_mapped_address(&res).or_else(|| Attribute::get_mapped_address(&res))
+    }
+}
+
+impl Stream for MappedAddressMonitor {
+    type Item = MappedAddressChange;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.changes.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use async_std::future;
+    use async_std::net::UdpSocket;
+
+    use super::*;
+
+    fn client_options() -> Options {
+        Options {
+            recv_timeout_ms: 1000,
+            rto_ms: 20,
+            rc: 1,
+            rm: 1,
+            ..Options::default()
+        }
+    }
+
+    fn monitor_options() -> MonitorOptions {
+        MonitorOptions {
+            interval_ms: 50,
+            jitter_ms: 0,
+            max_backoff_ms: 200,
+        }
+    }
+
+    // Answers the nth request (counted from 0) with the mapped address returned by the closure, or drops it,
+    // and reports the time each request arrived.
+    async fn respond<F>(mapped_addr: F) -> (SocketAddr, mpsc::UnboundedReceiver<Instant>)
+    where
+        F: Fn(usize) -> Option<SocketAddr> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        task::spawn(async move {
+            let mut buf = vec![0u8; 1024];
+            for n in 0.. {
+                let (len, source) = socket.recv_from(&mut buf).await.unwrap();
+                tx.unbounded_send(Instant::now()).ok();
+                let request = Message::from_raw(&buf[..len]).unwrap();
+                if let Some(mapped_addr) = mapped_addr(n) {
+                    let response = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+                        .transaction_id(request.get_transaction_id())
+                        .attr(AttributeValue::XORMappedAddress(mapped_addr))
+                        .build()
+                        .unwrap();
+                    socket.send_to(&response.to_raw(), source).await.unwrap();
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn mapped_address_changes_are_streamed() {
+        task::block_on(async {
+            let first: SocketAddr = "192.0.2.1:10000".parse().unwrap();
+            let second: SocketAddr = "192.0.2.1:20000".parse().unwrap();
+            let (server, _) =
+                respond(move |n| if n < 3 { Some(first) } else { Some(second) }).await;
+            let client = Client::new("127.0.0.1:0", Some(client_options()))
+                .await
+                .unwrap();
+
+            let mut monitor =
+                MappedAddressMonitor::start(client, vec![server], Some(monitor_options()));
+            let change = monitor.next().await.unwrap();
+            assert_eq!(change.old_addr, None);
+            assert_eq!(change.new_addr, first);
+            assert_eq!(change.server, server);
+            // Refreshes reporting the same address are not streamed.
+            let change = monitor.next().await.unwrap();
+            assert_eq!(change.old_addr, Some(first));
+            assert_eq!(change.new_addr, second);
+            assert_eq!(change.server, server);
+
+            let mut client = monitor.stop().await;
+            let res = client.binding_request(server, None).await.unwrap();
+            assert_eq!(
+                Attribute::get_xor_mapped_address(&res.message),
+                Some(second)
+            );
+        });
+    }
+
+    #[test]
+    fn refreshes_back_off_while_no_server_answers() {
+        task::block_on(async {
+            let mapped_addr: SocketAddr = "192.0.2.1:10000".parse().unwrap();
+            // The first 4 requests are dropped.
+            let (server, mut arrivals) =
+                respond(move |n| if n < 4 { None } else { Some(mapped_addr) }).await;
+            let client = Client::new("127.0.0.1:0", Some(client_options()))
+                .await
+                .unwrap();
+
+            let mut monitor =
+                MappedAddressMonitor::start(client, vec![server], Some(monitor_options()));
+            let change = future::timeout(Duration::from_secs(3), monitor.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(change.new_addr, mapped_addr);
+            let mut times = vec![];
+            for _ in 0..6 {
+                times.push(arrivals.next().await.unwrap());
+            }
+            monitor.stop().await;
+
+            let gaps: Vec<Duration> = times.windows(2).map(|w| w[1] - w[0]).collect();
+            // The interval doubles from 50 ms after each failure up to 200 ms...
+            assert!(gaps[0] >= Duration::from_millis(100), "{:?}", gaps);
+            assert!(gaps[1] >= Duration::from_millis(200), "{:?}", gaps);
+            assert!(gaps[2] >= Duration::from_millis(200), "{:?}", gaps);
+            assert!(gaps[2] < Duration::from_millis(400), "{:?}", gaps);
+            // ...and is reset once a server answers.
+            assert!(gaps[4] >= Duration::from_millis(50), "{:?}", gaps);
+            assert!(gaps[4] < Duration::from_millis(150), "{:?}", gaps);
+        });
+    }
+}
@@ -1,8 +1,15 @@
 //! This module implements some of the STUN protocol message processing based on RFC 8489 and RFC 5780.
-use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-use rand::{thread_rng, Rng};
+use bytes::BytesMut;
+use crc32fast::Hasher;
+use hmac::{Hmac, Mac};
+use md5::{Digest, Md5};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::Sha1;
+use sha2::Sha256;
 
 use super::error::*;
 
@@ -12,6 +19,26 @@ pub const MAGIC_COOKIE: u32 = 0x2112A442;
 // Methods
 /// Binding method
 pub const METHOD_BINDING: u16 = 0x0001;
+/// TURN Allocate method (RFC8656)
+pub const METHOD_ALLOCATE: u16 = 0x0003;
+/// TURN Refresh method (RFC8656)
+pub const METHOD_REFRESH: u16 = 0x0004;
+/// TURN Send method (RFC8656)
+pub const METHOD_SEND: u16 = 0x0006;
+/// TURN Data method (RFC8656)
+pub const METHOD_DATA: u16 = 0x0007;
+/// TURN CreatePermission method (RFC8656)
+pub const METHOD_CREATE_PERMISSION: u16 = 0x0008;
+/// TURN ChannelBind method (RFC8656)
+pub const METHOD_CHANNEL_BIND: u16 = 0x0009;
+/// TURN Connect method (RFC6062)
+pub const METHOD_CONNECT: u16 = 0x000A;
+/// TURN ConnectionBind method (RFC6062)
+pub const METHOD_CONNECTION_BIND: u16 = 0x000B;
+/// TURN ConnectionAttempt method (RFC6062)
+pub const METHOD_CONNECTION_ATTEMPT: u16 = 0x000C;
+/// GOOG-PING method
+pub const METHOD_GOOG_PING: u16 = 0x0080;
 
 // Classes
 /// A constant that represents a class request
@@ -25,6 +52,8 @@ pub const CLASS_ERROR_RESPONSE: u16 = 0x0110;
 
 /// STUN header size
 pub const HEADER_BYTE_SIZE: usize = 20;
+/// STUN transaction ID size
+pub const TRANSACTION_ID_BYTE_SIZE: usize = 12;
 
 // STUN Attributes
 /// MAPPED-ADDRESS attribute
@@ -33,8 +62,66 @@ pub const ATTR_MAPPED_ADDRESS: u16 = 0x0001;
 pub const ATTR_XOR_MAPPED_ADDRESS: u16 = 0x0020;
 /// ERROR-CODE attribute
 pub const ATTR_ERROR_CODE: u16 = 0x0009;
+/// UNKNOWN-ATTRIBUTES attribute
+pub const ATTR_UNKNOWN_ATTRIBUTES: u16 = 0x000A;
 /// SOFTWARE attribute
 pub const ATTR_SOFTWARE: u16 = 0x8022;
+/// USERNAME attribute
+pub const ATTR_USERNAME: u16 = 0x0006;
+/// REALM attribute
+pub const ATTR_REALM: u16 = 0x0014;
+/// NONCE attribute
+pub const ATTR_NONCE: u16 = 0x0015;
+/// USERHASH attribute
+pub const ATTR_USERHASH: u16 = 0x001E;
+/// ALTERNATE-SERVER attribute
+pub const ATTR_ALTERNATE_SERVER: u16 = 0x8023;
+/// ALTERNATE-DOMAIN attribute
+pub const ATTR_ALTERNATE_DOMAIN: u16 = 0x8003;
+/// MESSAGE-INTEGRITY attribute
+pub const ATTR_MESSAGE_INTEGRITY: u16 = 0x0008;
+
+/// MESSAGE-INTEGRITY-SHA256 attribute
+pub const ATTR_MESSAGE_INTEGRITY_SHA256: u16 = 0x001C;
+/// PASSWORD-ALGORITHM attribute
+pub const ATTR_PASSWORD_ALGORITHM: u16 = 0x001D;
+/// PASSWORD-ALGORITHMS attribute
+pub const ATTR_PASSWORD_ALGORITHMS: u16 = 0x8002;
+/// FINGERPRINT attribute
+pub const ATTR_FINGERPRINT: u16 = 0x8028;
+
+/// Size of the HMAC-SHA1 value of the MESSAGE-INTEGRITY attribute
+pub const MESSAGE_INTEGRITY_BYTE_SIZE: usize = 20;
+/// Size of the untruncated HMAC-SHA256 value of the MESSAGE-INTEGRITY-SHA256 attribute
+pub const MESSAGE_INTEGRITY_SHA256_BYTE_SIZE: usize = 32;
+/// Minimum size of a truncated MESSAGE-INTEGRITY-SHA256 value
+pub const MIN_MESSAGE_INTEGRITY_SHA256_BYTE_SIZE: usize = 16;
+/// Size of the FINGERPRINT value
+pub const FINGERPRINT_BYTE_SIZE: usize = 4;
+/// The value XOR'ed with the CRC-32 of the message to compute FINGERPRINT
+pub const FINGERPRINT_XOR_VALUE: u32 = 0x5354554e;
+/// STUN attribute header (type and length) size
+pub const ATTR_HEADER_BYTE_SIZE: usize = 4;
+/// Maximum length of a message after the header, and of an attribute value
+pub const MAX_LENGTH: usize = u16::MAX as usize;
+/// USERNAME values sent must be fewer than this many bytes
+pub const MAX_USERNAME_BYTE_SIZE: usize = 509;
+/// REALM values sent must be fewer than this many bytes
+pub const MAX_REALM_BYTE_SIZE: usize = 509;
+/// NONCE values sent must be fewer than this many bytes
+pub const MAX_NONCE_BYTE_SIZE: usize = 509;
+/// USERNAME values received must be fewer than this many bytes, as allowed by RFC5389
+pub const MAX_RECEIVED_USERNAME_BYTE_SIZE: usize = 513;
+/// Maximum size of the REALM and NONCE values received
+pub const MAX_RECEIVED_REALM_NONCE_BYTE_SIZE: usize = 763;
+/// Maximum size of the ALTERNATE-DOMAIN value
+pub const MAX_ALTERNATE_DOMAIN_BYTE_SIZE: usize = 255;
+/// Size of the USERHASH value
+pub const USERHASH_BYTE_SIZE: usize = 32;
+/// The reason phrase of ERROR-CODE must have fewer characters than this
+pub const MAX_REASON_PHRASE_CHARS: usize = 128;
+/// Maximum size of the reason phrase of a received ERROR-CODE
+pub const MAX_REASON_PHRASE_BYTE_SIZE: usize = 763;
 
 // RFC 5780 NAT Behavior Discovery
 /// OTHER-ADDRESS attribute
@@ -44,11 +131,46 @@ pub const ATTR_CHANGE_REQUEST: u16 = 0x0003;
 /// RESPONSE-ORIGIN attribute
 pub const ATTR_RESPONSE_ORIGIN: u16 = 0x802b;
 
+// RFC 3489 attributes that were removed by RFC 5389
+/// RESPONSE-ADDRESS attribute
+pub const ATTR_RESPONSE_ADDRESS: u16 = 0x0002;
+/// SOURCE-ADDRESS attribute
+pub const ATTR_SOURCE_ADDRESS: u16 = 0x0004;
+/// CHANGED-ADDRESS attribute
+pub const ATTR_CHANGED_ADDRESS: u16 = 0x0005;
+/// PASSWORD attribute
+pub const ATTR_PASSWORD: u16 = 0x0007;
+/// REFLECTED-FROM attribute
+pub const ATTR_REFLECTED_FROM: u16 = 0x000B;
+
+// RFC 8445 ICE attributes of connectivity checks
+/// PRIORITY attribute
+pub const ATTR_PRIORITY: u16 = 0x0024;
+/// USE-CANDIDATE attribute
+pub const ATTR_USE_CANDIDATE: u16 = 0x0025;
+/// ICE-CONTROLLED attribute
+pub const ATTR_ICE_CONTROLLED: u16 = 0x8029;
+/// ICE-CONTROLLING attribute
+pub const ATTR_ICE_CONTROLLING: u16 = 0x802A;
+
 /// The "change IP" flag for the CHANGE-REQUEST attribute.
 pub const CHANGE_REQUEST_IP_FLAG: u32 = 0x00000004;
 /// The "change port" flag for the CHANGE-REQUEST attribute.
 pub const CHANGE_REQUEST_PORT_FLAG: u32 = 0x00000002;
 
+// Password Algorithms
+/// MD5 password algorithm
+pub const PASSWORD_ALGORITHM_MD5: u16 = 0x0001;
+/// SHA-256 password algorithm
+pub const PASSWORD_ALGORITHM_SHA256: u16 = 0x0002;
+
+/// The prefix of a NONCE that carries the security feature set of the server.
+pub const NONCE_COOKIE: &str = "obMatJos2";
+/// The "Password algorithms" bit of the security feature set.
+pub const SECURITY_FEATURE_PASSWORD_ALGORITHMS: u32 = 0x800000;
+/// The "Username anonymity" bit of the security feature set.
+pub const SECURITY_FEATURE_USERNAME_ANONYMITY: u32 = 0x400000;
+
 pub const FAMILY_IPV4: u8 = 0x01;
 pub const FAMILY_IPV6: u8 = 0x02;
 
@@ -56,6 +178,16 @@ pub const FAMILY_IPV6: u8 = 0x02;
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Method {
     Binding,
+    Allocate,
+    Refresh,
+    Send,
+    Data,
+    CreatePermission,
+    ChannelBind,
+    Connect,
+    ConnectionBind,
+    ConnectionAttempt,
+    GoogPing,
     Unknown(u16),
 }
 
@@ -64,6 +196,16 @@ impl Method {
     pub fn from_u16(method: u16) -> Self {
         match method {
             METHOD_BINDING => Self::Binding,
+            METHOD_ALLOCATE => Self::Allocate,
+            METHOD_REFRESH => Self::Refresh,
+            METHOD_SEND => Self::Send,
+            METHOD_DATA => Self::Data,
+            METHOD_CREATE_PERMISSION => Self::CreatePermission,
+            METHOD_CHANNEL_BIND => Self::ChannelBind,
+            METHOD_CONNECT => Self::Connect,
+            METHOD_CONNECTION_BIND => Self::ConnectionBind,
+            METHOD_CONNECTION_ATTEMPT => Self::ConnectionAttempt,
+            METHOD_GOOG_PING => Self::GoogPing,
             _ => Self::Unknown(method),
         }
     }
@@ -72,7 +214,17 @@ impl Method {
     pub fn to_u16(&self) -> u16 {
         match self {
             Self::Binding => METHOD_BINDING,
-            Self::Unknown(method) => method.clone(),
+            Self::Allocate => METHOD_ALLOCATE,
+            Self::Refresh => METHOD_REFRESH,
+            Self::Send => METHOD_SEND,
+            Self::Data => METHOD_DATA,
+            Self::CreatePermission => METHOD_CREATE_PERMISSION,
+            Self::ChannelBind => METHOD_CHANNEL_BIND,
+            Self::Connect => METHOD_CONNECT,
+            Self::ConnectionBind => METHOD_CONNECTION_BIND,
+            Self::ConnectionAttempt => METHOD_CONNECTION_ATTEMPT,
+            Self::GoogPing => METHOD_GOOG_PING,
+            Self::Unknown(method) => *method,
         }
     }
 }
@@ -106,7 +258,7 @@ impl Class {
             Self::Indication => CLASS_INDICATION,
             Self::SuccessResponse => CLASS_SUCCESS_RESPONSE,
             Self::ErrorResponse => CLASS_ERROR_RESPONSE,
-            Self::Unknown(class) => class.clone(),
+            Self::Unknown(class) => *class,
         }
     }
 }
@@ -121,6 +273,18 @@ pub enum Attribute {
     ChangeRequest,
     ResponseOrigin,
     ErrorCode,
+    UnknownAttributes,
+    MessageIntegrity,
+    MessageIntegritySHA256,
+    PasswordAlgorithm,
+    PasswordAlgorithms,
+    Fingerprint,
+    Username,
+    Realm,
+    Nonce,
+    UserHash,
+    AlternateServer,
+    AlternateDomain,
     Unknown(u16),
 }
 
@@ -135,6 +299,18 @@ impl Attribute {
             ATTR_CHANGE_REQUEST => Self::ChangeRequest,
             ATTR_RESPONSE_ORIGIN => Self::ResponseOrigin,
             ATTR_ERROR_CODE => Self::ErrorCode,
+            ATTR_UNKNOWN_ATTRIBUTES => Self::UnknownAttributes,
+            ATTR_MESSAGE_INTEGRITY => Self::MessageIntegrity,
+            ATTR_MESSAGE_INTEGRITY_SHA256 => Self::MessageIntegritySHA256,
+            ATTR_PASSWORD_ALGORITHM => Self::PasswordAlgorithm,
+            ATTR_PASSWORD_ALGORITHMS => Self::PasswordAlgorithms,
+            ATTR_FINGERPRINT => Self::Fingerprint,
+            ATTR_USERNAME => Self::Username,
+            ATTR_REALM => Self::Realm,
+            ATTR_NONCE => Self::Nonce,
+            ATTR_USERHASH => Self::UserHash,
+            ATTR_ALTERNATE_SERVER => Self::AlternateServer,
+            ATTR_ALTERNATE_DOMAIN => Self::AlternateDomain,
             _ => Self::Unknown(attribute),
         }
     }
@@ -149,64 +325,116 @@ impl Attribute {
             Self::ChangeRequest => ATTR_CHANGE_REQUEST,
             Self::ResponseOrigin => ATTR_RESPONSE_ORIGIN,
             Self::ErrorCode => ATTR_ERROR_CODE,
-            Self::Unknown(attribute) => attribute.clone(),
+            Self::UnknownAttributes => ATTR_UNKNOWN_ATTRIBUTES,
+            Self::MessageIntegrity => ATTR_MESSAGE_INTEGRITY,
+            Self::MessageIntegritySHA256 => ATTR_MESSAGE_INTEGRITY_SHA256,
+            Self::PasswordAlgorithm => ATTR_PASSWORD_ALGORITHM,
+            Self::PasswordAlgorithms => ATTR_PASSWORD_ALGORITHMS,
+            Self::Fingerprint => ATTR_FINGERPRINT,
+            Self::Username => ATTR_USERNAME,
+            Self::Realm => ATTR_REALM,
+            Self::Nonce => ATTR_NONCE,
+            Self::UserHash => ATTR_USERHASH,
+            Self::AlternateServer => ATTR_ALTERNATE_SERVER,
+            Self::AlternateDomain => ATTR_ALTERNATE_DOMAIN,
+            Self::Unknown(attribute) => *attribute,
         }
     }
 
+    /// Returns true if the attribute type is in the comprehension-required range (0x0000-0x7FFF).
+    /// A message containing such an attribute that is not understood must not be processed as usual.
+    pub fn is_comprehension_required(&self) -> bool {
+        self.to_u16() < 0x8000
+    }
+
     /// Gets the value of the MAPPED-ADDRESS attribute from Message.
     pub fn get_mapped_address(message: &Message) -> Option<SocketAddr> {
         Self::decode_simple_address_attribute(message, Self::MappedAddress)
     }
 
+    /// Generates a value for the MAPPED-ADDRESS attribute.
+    pub fn generate_mapped_address_value(addr: &SocketAddr) -> Vec<u8> {
+        encode_address(addr)
+    }
+
     /// Gets the value of the XOR-MAPPED-ADDRESS attribute from Message.
     pub fn get_xor_mapped_address(message: &Message) -> Option<SocketAddr> {
-        let attr_value = message.get_raw_attr_value(Self::XORMappedAddress)?;
-        let family = attr_value[1];
-        // RFC8489: X-Port is computed by XOR'ing the mapped port with the most significant 16 bits of the magic cookie.
-        let mc_bytes = MAGIC_COOKIE.to_be_bytes();
-        let port = u16::from_be_bytes([attr_value[2], attr_value[3]])
-            ^ u16::from_be_bytes([mc_bytes[0], mc_bytes[1]]);
-        match family {
-            FAMILY_IPV4 => {
-                // RFC8489: If the IP address family is IPv4, X-Address is computed by XOR'ing the mapped IP address with the magic cookie.
-                let encoded_ip = &attr_value[4..];
-                let b: Vec<u8> = encoded_ip
-                    .iter()
-                    .zip(&MAGIC_COOKIE.to_be_bytes())
-                    .map(|(b, m)| b ^ m)
-                    .collect();
-                let ip_addr = bytes_to_ip_addr(family, b)?;
-                Some(SocketAddr::new(ip_addr, port))
-            }
-            FAMILY_IPV6 => {
-                // RFC8489: If the IP address family is IPv6, X-Address is computed by XOR'ing the mapped IP address with the concatenation of the magic cookie and the 96-bit transaction ID.
-                let encoded_ip = &attr_value[4..];
-                let mut mc_ti: Vec<u8> = vec![];
-                mc_ti.extend(&MAGIC_COOKIE.to_be_bytes());
-                mc_ti.extend(&message.header.transaction_id);
-                let b: Vec<u8> = encoded_ip.iter().zip(&mc_ti).map(|(b, m)| b ^ m).collect();
-                let ip_addr = bytes_to_ip_addr(family, b)?;
-                Some(SocketAddr::new(ip_addr, port))
-            }
+        match message.get_attr_value(Self::XORMappedAddress)?.ok()? {
+            AttributeValue::XORMappedAddress(addr) => Some(addr),
             _ => None,
         }
     }
 
+    /// Generates a value for the XOR-MAPPED-ADDRESS attribute.
+    /// The transaction ID of the message is needed to XOR IPv6 addresses.
+    pub fn generate_xor_mapped_address_value(
+        addr: &SocketAddr,
+        transaction_id: &TransactionId,
+    ) -> Vec<u8> {
+        xor_address(&encode_address(addr), transaction_id)
+    }
+
     /// Gets the value of the SOFTWARE attribute from message.
     pub fn get_software(message: &Message) -> Option<String> {
-        let attr_value = message.get_raw_attr_value(Self::Software)?;
-        String::from_utf8(attr_value).ok()
+        match message.get_attr_value(Self::Software)?.ok()? {
+            AttributeValue::Software(software) => Some(software),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of the USERNAME attribute from Message.
+    pub fn get_username(message: &Message) -> Option<String> {
+        match message.get_attr_value(Self::Username)?.ok()? {
+            AttributeValue::Username(username) => Some(username),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of the REALM attribute from Message.
+    pub fn get_realm(message: &Message) -> Option<String> {
+        match message.get_attr_value(Self::Realm)?.ok()? {
+            AttributeValue::Realm(realm) => Some(realm),
+            _ => None,
+        }
+    }
+
+    /// Gets the value of the NONCE attribute from Message.
+    pub fn get_nonce(message: &Message) -> Option<String> {
+        match message.get_attr_value(Self::Nonce)?.ok()? {
+            AttributeValue::Nonce(nonce) => Some(nonce),
+            _ => None,
+        }
     }
 
     /// Gets the value of the ERROR-CODE attribute from Message.
     pub fn get_error_code(message: &Message) -> Option<ErrorCode> {
-        let attr_value = message.get_raw_attr_value(Self::ErrorCode)?;
-        let class = (attr_value[2] as u16) * 100;
-        let number = attr_value[3] as u16;
-        let code = class + number;
-        let reason = String::from_utf8(attr_value[4..].to_vec())
-            .unwrap_or(String::from("cannot parse error reason"));
-        Some(ErrorCode::from(code, reason))
+        match message.get_attr_value(Self::ErrorCode)?.ok()? {
+            AttributeValue::ErrorCode { code, reason } => Some(ErrorCode::from(code, reason)),
+            _ => None,
+        }
+    }
+
+    /// Generates a value for the ERROR-CODE attribute.
+    /// The code must be between 300 and 699 and the reason phrase must have fewer than 128 characters
+    /// as described in RFC8489 Section 14.8.
+    pub fn generate_error_code_value(error_code: &ErrorCode) -> Result<Vec<u8>, STUNClientError> {
+        encode_error_code(error_code.code(), error_code.reason())
+    }
+
+    /// Gets the value of the UNKNOWN-ATTRIBUTES attribute from Message.
+    pub fn get_unknown_attributes(message: &Message) -> Option<Vec<Attribute>> {
+        match message.get_attr_value(Self::UnknownAttributes)?.ok()? {
+            AttributeValue::UnknownAttributes(attrs) => Some(attrs),
+            _ => None,
+        }
+    }
+
+    /// Generates a value for the UNKNOWN-ATTRIBUTES attribute of a 420 Unknown Attribute response.
+    pub fn generate_unknown_attributes_value(attrs: &[Attribute]) -> Vec<u8> {
+        attrs
+            .iter()
+            .flat_map(|a| a.to_u16().to_be_bytes())
+            .collect()
     }
 
     /// Gets the value of the OTHER-ADDRESS attribute from Message.
@@ -216,11 +444,61 @@ impl Attribute {
         Self::decode_simple_address_attribute(message, Self::OtherAddress)
     }
 
+    /// Generates a value for the OTHER-ADDRESS attribute.
+    pub fn generate_other_address_value(addr: &SocketAddr) -> Vec<u8> {
+        encode_address(addr)
+    }
+
     /// Gets the value of the RESPONSE-ORIGIN attribute from Message.
     pub fn get_response_origin(message: &Message) -> Option<SocketAddr> {
         Self::decode_simple_address_attribute(message, Self::ResponseOrigin)
     }
 
+    /// Generates a value for the RESPONSE-ORIGIN attribute.
+    pub fn generate_response_origin_value(addr: &SocketAddr) -> Vec<u8> {
+        encode_address(addr)
+    }
+
+    /// Gets the value of the USERHASH attribute from Message.
+    pub fn get_userhash(message: &Message) -> Option<Vec<u8>> {
+        match message.get_attr_value(Self::UserHash)?.ok()? {
+            AttributeValue::UserHash(userhash) => Some(userhash),
+            _ => None,
+        }
+    }
+
+    /// Generates a value for the USERHASH attribute.
+    /// RFC8489: userhash = SHA-256(OpaqueString(username) ":" OpaqueString(realm))
+    pub fn generate_userhash_value(
+        username: &str,
+        realm: &str,
+    ) -> Result<Vec<u8>, STUNClientError> {
+        let input = format!(
+            "{}:{}",
+            opaque_string(Self::Username, username)?,
+            opaque_string(Self::Realm, realm)?
+        );
+        Ok(Sha256::digest(input.as_bytes()).to_vec())
+    }
+
+    /// Gets the value of the ALTERNATE-SERVER attribute from Message.
+    pub fn get_alternate_server(message: &Message) -> Option<SocketAddr> {
+        Self::decode_simple_address_attribute(message, Self::AlternateServer)
+    }
+
+    /// Generates a value for the ALTERNATE-SERVER attribute.
+    pub fn generate_alternate_server_value(addr: &SocketAddr) -> Vec<u8> {
+        encode_address(addr)
+    }
+
+    /// Gets the value of the ALTERNATE-DOMAIN attribute from Message.
+    pub fn get_alternate_domain(message: &Message) -> Option<String> {
+        match message.get_attr_value(Self::AlternateDomain)?.ok()? {
+            AttributeValue::AlternateDomain(domain) => Some(domain),
+            _ => None,
+        }
+    }
+
     /// Generates a value for the CHANGE-REQUEST attribute.
     pub fn generate_change_request_value(change_ip: bool, change_port: bool) -> Vec<u8> {
         let mut value: u32 = 0;
@@ -235,78 +513,524 @@ impl Attribute {
         value.to_be_bytes().to_vec()
     }
 
+    /// Gets the value of the PASSWORD-ALGORITHMS attribute from Message.
+    pub fn get_password_algorithms(message: &Message) -> Option<Vec<PasswordAlgorithm>> {
+        match message.get_attr_value(Self::PasswordAlgorithms)?.ok()? {
+            AttributeValue::PasswordAlgorithms(algorithms) => Some(algorithms),
+            _ => None,
+        }
+    }
+
+    /// Generates a value for the PASSWORD-ALGORITHMS attribute.
+    pub fn generate_password_algorithms_value(algorithms: &[PasswordAlgorithm]) -> Vec<u8> {
+        algorithms.iter().flat_map(|a| a.encode()).collect()
+    }
+
+    /// Gets the value of the PASSWORD-ALGORITHM attribute from Message.
+    pub fn get_password_algorithm(message: &Message) -> Option<PasswordAlgorithm> {
+        match message.get_attr_value(Self::PasswordAlgorithm)?.ok()? {
+            AttributeValue::PasswordAlgorithm(algorithm) => Some(algorithm),
+            _ => None,
+        }
+    }
+
+    /// Generates a value for the PASSWORD-ALGORITHM attribute.
+    pub fn generate_password_algorithm_value(algorithm: PasswordAlgorithm) -> Vec<u8> {
+        algorithm.encode()
+    }
+
     pub fn decode_simple_address_attribute(message: &Message, attr: Self) -> Option<SocketAddr> {
         let attr_value = message.get_raw_attr_value(attr)?;
-        let family = attr_value[1];
-        let port = u16::from_be_bytes([attr_value[2], attr_value[3]]);
-        let ip_addr = bytes_to_ip_addr(family, attr_value[4..].to_vec())?;
-        Some(SocketAddr::new(ip_addr, port))
+        decode_address(attr, &attr_value).ok()
+    }
+}
+
+/// Enum representing the typed value of a STUN attribute.
+/// Attributes without a dedicated variant are represented by Opaque.
+/// Opaque values are encoded as-is, e.g. to echo the REALM and NONCE received from a server.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AttributeValue {
+    MappedAddress(SocketAddr),
+    XORMappedAddress(SocketAddr),
+    OtherAddress(SocketAddr),
+    ResponseOrigin(SocketAddr),
+    Software(String),
+    Username(String),
+    Realm(String),
+    Nonce(String),
+    UserHash(Vec<u8>),
+    AlternateServer(SocketAddr),
+    AlternateDomain(String),
+    ErrorCode { code: u16, reason: String },
+    ChangeRequest { change_ip: bool, change_port: bool },
+    UnknownAttributes(Vec<Attribute>),
+    PasswordAlgorithms(Vec<PasswordAlgorithm>),
+    PasswordAlgorithm(PasswordAlgorithm),
+    MessageIntegrity(Vec<u8>),
+    MessageIntegritySHA256(Vec<u8>),
+    Fingerprint(u32),
+    Opaque(Attribute, Vec<u8>),
+}
+
+impl AttributeValue {
+    /// Get the attribute type of the value.
+    pub fn attribute(&self) -> Attribute {
+        match self {
+            Self::MappedAddress(_) => Attribute::MappedAddress,
+            Self::XORMappedAddress(_) => Attribute::XORMappedAddress,
+            Self::OtherAddress(_) => Attribute::OtherAddress,
+            Self::ResponseOrigin(_) => Attribute::ResponseOrigin,
+            Self::Software(_) => Attribute::Software,
+            Self::Username(_) => Attribute::Username,
+            Self::Realm(_) => Attribute::Realm,
+            Self::Nonce(_) => Attribute::Nonce,
+            Self::UserHash(_) => Attribute::UserHash,
+            Self::AlternateServer(_) => Attribute::AlternateServer,
+            Self::AlternateDomain(_) => Attribute::AlternateDomain,
+            Self::ErrorCode { .. } => Attribute::ErrorCode,
+            Self::ChangeRequest { .. } => Attribute::ChangeRequest,
+            Self::UnknownAttributes(_) => Attribute::UnknownAttributes,
+            Self::PasswordAlgorithms(_) => Attribute::PasswordAlgorithms,
+            Self::PasswordAlgorithm(_) => Attribute::PasswordAlgorithm,
+            Self::MessageIntegrity(_) => Attribute::MessageIntegrity,
+            Self::MessageIntegritySHA256(_) => Attribute::MessageIntegritySHA256,
+            Self::Fingerprint(_) => Attribute::Fingerprint,
+            Self::Opaque(attr, _) => *attr,
+        }
+    }
+
+    /// Encodes the value into raw attribute bytes (without padding).
+    /// The transaction ID is needed to encode XOR-MAPPED-ADDRESS.
+    /// USERNAME and REALM are prepared with the OpaqueString profile, and values exceeding
+    /// the size limits of RFC8489 are rejected: USERNAME, REALM and NONCE must be fewer than 509 bytes.
+    /// Opaque values are neither prepared nor limited.
+    pub fn encode(&self, transaction_id: &TransactionId) -> Result<Vec<u8>, STUNClientError> {
+        let attr = self.attribute();
+        let encoded = match self {
+            Self::MappedAddress(addr)
+            | Self::OtherAddress(addr)
+            | Self::ResponseOrigin(addr)
+            | Self::AlternateServer(addr) => encode_address(addr),
+            Self::XORMappedAddress(addr) => {
+                Attribute::generate_xor_mapped_address_value(addr, transaction_id)
+            }
+            Self::Username(s) | Self::Realm(s) => opaque_string(attr, s)?.into_bytes(),
+            Self::AlternateDomain(s) => {
+                if !s.is_ascii() {
+                    return Err(STUNClientError::InvalidAttributeValueError(
+                        attr,
+                        String::from("domain name must be ASCII"),
+                    ));
+                }
+                s.as_bytes().to_vec()
+            }
+            Self::Software(s) | Self::Nonce(s) => s.as_bytes().to_vec(),
+            Self::ErrorCode { code, reason } => encode_error_code(*code, reason)?,
+            Self::ChangeRequest {
+                change_ip,
+                change_port,
+            } => Attribute::generate_change_request_value(*change_ip, *change_port),
+            Self::UnknownAttributes(attrs) => Attribute::generate_unknown_attributes_value(attrs),
+            Self::PasswordAlgorithms(algorithms) => {
+                Attribute::generate_password_algorithms_value(algorithms)
+            }
+            Self::PasswordAlgorithm(algorithm) => algorithm.encode(),
+            Self::MessageIntegrity(b)
+            | Self::MessageIntegritySHA256(b)
+            | Self::UserHash(b)
+            | Self::Opaque(_, b) => b.clone(),
+            Self::Fingerprint(crc) => crc.to_be_bytes().to_vec(),
+        };
+
+        match encoded_len_limit(attr) {
+            Some(limit) if encoded.len() >= limit && !matches!(self, Self::Opaque(..)) => Err(
+                STUNClientError::InvalidAttributeLengthError(attr, encoded.len()),
+            ),
+            _ => Ok(encoded),
+        }
+    }
+
+    /// Decodes raw attribute bytes into a typed value.
+    /// The transaction ID is needed to decode XOR-MAPPED-ADDRESS.
+    /// RFC8489: REALM and NONCE are accepted up to 763 bytes, and USERNAME up to 512 bytes as in RFC5389.
+    pub fn decode(
+        attr: Attribute,
+        value: &[u8],
+        transaction_id: &TransactionId,
+    ) -> Result<Self, STUNClientError> {
+        let invalid_length = || STUNClientError::InvalidAttributeLengthError(attr, value.len());
+        if matches!(decoded_len_limit(attr), Some(limit) if value.len() >= limit) {
+            return Err(invalid_length());
+        }
+        let decode_string = |v: &[u8]| {
+            String::from_utf8(v.to_vec()).map_err(|e| {
+                STUNClientError::InvalidAttributeValueError(attr, e.utf8_error().to_string())
+            })
+        };
+        let decoded = match attr {
+            Attribute::MappedAddress => Self::MappedAddress(decode_address(attr, value)?),
+            Attribute::XORMappedAddress => {
+                Self::XORMappedAddress(decode_address(attr, &xor_address(value, transaction_id))?)
+            }
+            Attribute::OtherAddress => Self::OtherAddress(decode_address(attr, value)?),
+            Attribute::ResponseOrigin => Self::ResponseOrigin(decode_address(attr, value)?),
+            Attribute::Software => Self::Software(decode_string(value)?),
+            Attribute::Username => Self::Username(decode_string(value)?),
+            Attribute::Realm => Self::Realm(decode_string(value)?),
+            Attribute::Nonce => Self::Nonce(decode_string(value)?),
+            Attribute::UserHash => {
+                Self::UserHash(fixed_size::<USERHASH_BYTE_SIZE>(attr, value)?.to_vec())
+            }
+            Attribute::AlternateServer => Self::AlternateServer(decode_address(attr, value)?),
+            Attribute::AlternateDomain => Self::AlternateDomain(decode_string(value)?),
+            Attribute::ErrorCode => {
+                let head = value.get(..4).ok_or_else(invalid_length)?;
+                if value.len() - 4 > MAX_REASON_PHRASE_BYTE_SIZE {
+                    return Err(invalid_length());
+                }
+                let (class, number) = (head[2] & 0x07, head[3]);
+                if !(3..=6).contains(&class) || number > 99 {
+                    return Err(STUNClientError::InvalidAttributeValueError(
+                        attr,
+                        format!("invalid class {} or number {}", class, number),
+                    ));
+                }
+                let code = class as u16 * 100 + number as u16;
+                let reason = String::from_utf8_lossy(&value[4..]).into_owned();
+                Self::ErrorCode { code, reason }
+            }
+            Attribute::ChangeRequest => {
+                let flags = u32::from_be_bytes(fixed_size(attr, value)?);
+                Self::ChangeRequest {
+                    change_ip: flags & CHANGE_REQUEST_IP_FLAG != 0,
+                    change_port: flags & CHANGE_REQUEST_PORT_FLAG != 0,
+                }
+            }
+            Attribute::UnknownAttributes => {
+                if value.len() % 2 != 0 {
+                    return Err(invalid_length());
+                }
+                Self::UnknownAttributes(
+                    value
+                        .chunks_exact(2)
+                        .map(|c| Attribute::from_u16(u16::from_be_bytes([c[0], c[1]])))
+                        .collect(),
+                )
+            }
+            Attribute::PasswordAlgorithms => {
+                let mut algorithms = vec![];
+                let mut rest = value;
+                while !rest.is_empty() {
+                    let (algorithm, next) =
+                        PasswordAlgorithm::decode(rest).ok_or_else(invalid_length)?;
+                    algorithms.push(algorithm);
+                    rest = next;
+                }
+                Self::PasswordAlgorithms(algorithms)
+            }
+            Attribute::PasswordAlgorithm => Self::PasswordAlgorithm(
+                PasswordAlgorithm::decode(value)
+                    .ok_or_else(invalid_length)?
+                    .0,
+            ),
+            Attribute::MessageIntegrity => Self::MessageIntegrity(
+                fixed_size::<MESSAGE_INTEGRITY_BYTE_SIZE>(attr, value)?.to_vec(),
+            ),
+            Attribute::MessageIntegritySHA256 => {
+                if !is_valid_message_integrity_sha256_length(value.len()) {
+                    return Err(invalid_length());
+                }
+                Self::MessageIntegritySHA256(value.to_vec())
+            }
+            Attribute::Fingerprint => {
+                Self::Fingerprint(u32::from_be_bytes(fixed_size(attr, value)?))
+            }
+            Attribute::Unknown(_) => Self::Opaque(attr, value.to_vec()),
+        };
+
+        Ok(decoded)
+    }
+}
+
+/// Enum representing the algorithm used to derive the long-term credential key
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PasswordAlgorithm {
+    MD5,
+    SHA256,
+    Unknown(u16),
+}
+
+impl PasswordAlgorithm {
+    /// Convert from u16 to PasswordAlgorithm.
+    pub fn from_u16(algorithm: u16) -> Self {
+        match algorithm {
+            PASSWORD_ALGORITHM_MD5 => Self::MD5,
+            PASSWORD_ALGORITHM_SHA256 => Self::SHA256,
+            _ => Self::Unknown(algorithm),
+        }
+    }
+
+    /// Convert from PasswordAlgorithm to u16.
+    pub fn to_u16(&self) -> u16 {
+        match self {
+            Self::MD5 => PASSWORD_ALGORITHM_MD5,
+            Self::SHA256 => PASSWORD_ALGORITHM_SHA256,
+            Self::Unknown(algorithm) => *algorithm,
+        }
+    }
+
+    /// Selects the password algorithm and the integrity attribute to use in requests
+    /// from the PASSWORD-ALGORITHMS attribute of a server response (RFC8489 Section 9.2.4).
+    /// SHA-256 is preferred whenever the server offers it.
+    /// MESSAGE-INTEGRITY (HMAC-SHA1) is only selected when the server does not send PASSWORD-ALGORITHMS,
+    /// which means the server only implements RFC5389.
+    pub fn negotiate(response: &Message) -> Result<(Self, Attribute), STUNClientError> {
+        if response
+            .get_raw_attr_value(Attribute::PasswordAlgorithms)
+            .is_none()
+        {
+            return Ok((Self::MD5, Attribute::MessageIntegrity));
+        }
+
+        let offered = match response.get_attr_value(Attribute::PasswordAlgorithms) {
+            Some(Ok(AttributeValue::PasswordAlgorithms(offered))) => offered,
+            Some(Err(e)) => return Err(e),
+            _ => vec![],
+        };
+        [Self::SHA256, Self::MD5]
+            .iter()
+            .find(|a| offered.contains(a))
+            .map(|a| (*a, Attribute::MessageIntegritySHA256))
+            .ok_or_else(|| STUNClientError::NotSupportedError(String::from("PASSWORD-ALGORITHMS")))
+    }
+
+    /// Computes the long-term credential key (RFC8489 Section 9.2.2).
+    pub fn derive_key(
+        &self,
+        username: &str,
+        realm: &str,
+        password: &str,
+    ) -> Result<Vec<u8>, STUNClientError> {
+        // RFC8489: key = H(OpaqueString(username) ":" OpaqueString(realm) ":" OpaqueString(password))
+        let password = stringprep::saslprep(password).map_err(|e| {
+            STUNClientError::AuthenticationError(format!("invalid password: {}", e))
+        })?;
+        let input = format!(
+            "{}:{}:{}",
+            opaque_string(Attribute::Username, username)?,
+            opaque_string(Attribute::Realm, realm)?,
+            password
+        );
+        match self {
+            Self::MD5 => Ok(Md5::digest(input.as_bytes()).to_vec()),
+            Self::SHA256 => Ok(Sha256::digest(input.as_bytes()).to_vec()),
+            Self::Unknown(algorithm) => Err(STUNClientError::NotSupportedError(format!(
+                "password algorithm {:#06x}",
+                algorithm
+            ))),
+        }
+    }
+
+    // Neither MD5 nor SHA-256 has parameters, but unknown algorithms may.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend(&self.to_u16().to_be_bytes());
+        bytes.extend(&0u16.to_be_bytes());
+        bytes
+    }
+
+    fn decode(buf: &[u8]) -> Option<(Self, &[u8])> {
+        if buf.len() < 4 {
+            return None;
+        }
+
+        let algorithm = Self::from_u16(u16::from_be_bytes([buf[0], buf[1]]));
+        let params_length = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        // RFC8489: Parameters are padded to a multiple of 4 bytes.
+        let rest = buf.get(4 + params_length + padding_len(params_length)..)?;
+        Some((algorithm, rest))
+    }
+}
+
+/// The security features a server advertises with the nonce cookie (RFC8489 Section 9.2).
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct SecurityFeatures {
+    pub password_algorithms: bool,
+    pub username_anonymity: bool,
+}
+
+impl SecurityFeatures {
+    /// Decodes the security feature set from a NONCE.
+    /// Returns None if the nonce does not start with the nonce cookie.
+    pub fn from_nonce(nonce: &str) -> Option<Self> {
+        // RFC8489: The nonce cookie is followed by the 24-bit Security Feature Set encoded as 4 characters using base64.
+        let encoded = nonce.strip_prefix(NONCE_COOKIE)?.get(..4)?;
+        let bits = encoded.bytes().try_fold(0u32, |bits, c| {
+            let sextet = match c {
+                b'A'..=b'Z' => c - b'A',
+                b'a'..=b'z' => c - b'a' + 26,
+                b'0'..=b'9' => c - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => return None,
+            };
+            Some(bits << 6 | sextet as u32)
+        })?;
+
+        Some(SecurityFeatures {
+            password_algorithms: bits & SECURITY_FEATURE_PASSWORD_ALGORITHMS != 0,
+            username_anonymity: bits & SECURITY_FEATURE_USERNAME_ANONYMITY != 0,
+        })
     }
 }
 
 /// Struct representing STUN message
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq)]
 pub struct Message {
     header: Header,
-    attributes: Option<HashMap<Attribute, Vec<u8>>>,
+    // Attributes in wire order, including duplicates.
+    attributes: Vec<(Attribute, Vec<u8>)>,
+    // The bytes the message was decoded from, used to verify MESSAGE-INTEGRITY.
+    // Cleared when the message is modified.
+    raw: Option<Vec<u8>>,
 }
 
 impl Message {
     /// Create a STUN Message.
+    /// Attributes are encoded in the given order.
+    /// Their lengths are not checked, see [`Message::add`] and [`Message::encode_into`].
+    /// MESSAGE-INTEGRITY, MESSAGE-INTEGRITY-SHA256 and FINGERPRINT followed by other attributes are removed,
+    /// because they would no longer be valid.
     pub fn new(
         method: Method,
         class: Class,
-        attributes: Option<HashMap<Attribute, Vec<u8>>>,
+        attributes: Option<Vec<(Attribute, Vec<u8>)>>,
     ) -> Message {
-        let attr_type_byte_size = 2;
-        let attr_length_byte_size = 2;
-        let length: u16 = if let Some(attributes) = &attributes {
-            attributes
-                .iter()
-                .map(|e| attr_type_byte_size + attr_length_byte_size + e.1.len() as u16)
-                .sum()
-        } else {
-            0
+        let mut msg = Message {
+            header: Header::new(method, class, 0, TransactionId::random()),
+            attributes: attributes.unwrap_or_default(),
+            raw: None,
         };
+        msg.remove_stale_trailers();
+        msg.update_length();
+        msg
+    }
+
+    /// Create a STUN message from raw bytes.
+    /// If the message contains FINGERPRINT, it is validated.
+    /// RFC8489: Attributes following MESSAGE-INTEGRITY other than MESSAGE-INTEGRITY-SHA256 and FINGERPRINT are ignored,
+    /// and a message with attributes following FINGERPRINT is rejected.
+    /// The header is validated as described in RFC8489 Section 6.3, see [`Header::from_raw`].
+    pub fn from_raw(buf: &[u8]) -> Result<Message, STUNClientError> {
+        Message::decode(buf, true)
+    }
 
-        let transaction_id: Vec<u8> = thread_rng().gen::<[u8; 12]>().to_vec();
+    /// Create a STUN Message from raw bytes without checking the magic cookie.
+    /// This accepts messages from RFC3489 implementations, see [`Header::from_raw_lenient`].
+    pub fn from_raw_lenient(buf: &[u8]) -> Result<Message, STUNClientError> {
+        Message::decode(buf, false)
+    }
 
-        Message {
-            header: Header::new(method, class, length, transaction_id),
-            attributes: attributes,
+    fn decode(buf: &[u8], check_magic_cookie: bool) -> Result<Message, STUNClientError> {
+        MessageRef::decode(buf, check_magic_cookie).map(|msg| msg.to_message())
+    }
+
+    /// Returns whether the raw bytes are a STUN message, to demultiplex STUN from other protocols
+    /// sharing the same socket as described in RFC7983.
+    /// The first byte must be in the range 0 to 3, the header must be valid as in [`Message::from_raw`]
+    /// and FINGERPRINT must match if present. The attributes are not decoded.
+    pub fn is_stun(buf: &[u8]) -> bool {
+        Message::is_stun_with(buf, true)
+    }
+
+    /// Same as [`Message::is_stun`], but does not check the magic cookie.
+    pub fn is_stun_lenient(buf: &[u8]) -> bool {
+        Message::is_stun_with(buf, false)
+    }
+
+    fn is_stun_with(buf: &[u8], check_magic_cookie: bool) -> bool {
+        // RFC7983: The first byte of ZRTP and DTLS packets may also pass the header validation,
+        // which only requires the most significant 2 bits to be zero.
+        !buf.is_empty()
+            && buf[0] <= 3
+            && Header::validate(buf, check_magic_cookie).is_ok()
+            && verify_fingerprint(buf).is_ok()
+    }
+
+    /// Converts a Message to a STUN protocol message raw bytes.
+    /// Attributes are encoded in order and their values are padded with zeros to a multiple of 4 bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the attributes do not fit in the length fields, which only [`Message::new`] allows.
+    /// Use [`Message::encode_into`] to handle the error instead.
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.encoded_len()];
+        // The buffer is allocated with the exact size, so encoding only fails if the message is too large.
+        if let Err(e) = self.encode_into(&mut bytes) {
+            panic!("cannot encode the message: {}", e);
         }
+        bytes
     }
 
-    /// Create a STUN message from raw bytes.
-    pub fn from_raw(buf: &[u8]) -> Result<Message, STUNClientError> {
-        if buf.len() < HEADER_BYTE_SIZE {
-            return Err(STUNClientError::ParseError());
+    /// Encodes a Message into the buffer and returns the number of bytes written.
+    /// Fails if an attribute value or the whole message does not fit in its 16-bit length field.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, STUNClientError> {
+        if let Some((k, v)) = self.attributes.iter().find(|(_, v)| v.len() > MAX_LENGTH) {
+            return Err(STUNClientError::InvalidAttributeLengthError(*k, v.len()));
+        }
+        let len = self.encoded_len();
+        if len - HEADER_BYTE_SIZE > MAX_LENGTH {
+            return Err(STUNClientError::MessageTooLargeError(
+                len - HEADER_BYTE_SIZE,
+            ));
         }
+        if buf.len() < len {
+            return Err(STUNClientError::BufferTooSmallError(len, buf.len()));
+        }
+
+        buf[..2].copy_from_slice(&self.header.message_type().to_be_bytes());
+        buf[2..4].copy_from_slice(&self.header.length.to_be_bytes());
+        buf[4..8].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        buf[8..HEADER_BYTE_SIZE].copy_from_slice(self.header.transaction_id.as_bytes());
 
-        let header = Header::from_raw(&buf[..HEADER_BYTE_SIZE])?;
-        let mut attrs = None;
-        if buf.len() > HEADER_BYTE_SIZE {
-            attrs = Some(Message::decode_attrs(&buf[HEADER_BYTE_SIZE..])?);
+        let mut offset = HEADER_BYTE_SIZE;
+        for (k, v) in self.attributes.iter() {
+            buf[offset..offset + 2].copy_from_slice(&k.to_u16().to_be_bytes());
+            buf[offset + 2..offset + 4].copy_from_slice(&(v.len() as u16).to_be_bytes());
+            offset += ATTR_HEADER_BYTE_SIZE;
+            buf[offset..offset + v.len()].copy_from_slice(v);
+            offset += v.len();
+            let padding = padding_len(v.len());
+            buf[offset..offset + padding].fill(0);
+            offset += padding;
         }
 
-        Ok(Message {
-            header: header,
-            attributes: attrs,
-        })
+        Ok(len)
     }
 
-    /// Converts a Message to a STUN protocol message raw bytes.
-    pub fn to_raw(&self) -> Vec<u8> {
-        let mut bytes = self.header.to_raw();
-        if let Some(attributes) = &self.attributes {
-            for (k, v) in attributes.iter() {
-                bytes.extend(&k.to_u16().to_be_bytes());
-                bytes.extend(&(v.len() as u16).to_be_bytes());
-                bytes.extend(v);
-            }
+    /// Appends the encoded Message to the buffer and returns the number of bytes written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the message is too large, see [`Message::to_raw`].
+    pub fn encode_into_bytes(&self, buf: &mut BytesMut) -> usize {
+        let start = buf.len();
+        buf.resize(start + self.encoded_len(), 0);
+        // The buffer has just been resized to fit the message, so encoding only fails if the message is too large.
+        if let Err(e) = self.encode_into(&mut buf[start..]) {
+            panic!("cannot encode the message: {}", e);
         }
+        buf.len() - start
+    }
 
-        bytes
+    fn encoded_len(&self) -> usize {
+        HEADER_BYTE_SIZE
+            + self
+                .attributes
+                .iter()
+                .map(|(_, v)| padded_attr_len(v.len()))
+                .sum::<usize>()
     }
 
     /// Get the method from Message.
@@ -320,88 +1044,332 @@ impl Message {
     }
 
     /// Get the raw attribute bytes from Message.
+    /// If the attribute appears more than once, the first one is returned.
     pub fn get_raw_attr_value(&self, attr: Attribute) -> Option<Vec<u8>> {
         self.attributes
-            .as_ref()?
-            .get(&attr)
-            .and_then(|v| Some(v.clone()))
+            .iter()
+            .find(|(k, _)| *k == attr)
+            .map(|(_, v)| v.clone())
+    }
+
+    /// Get the raw bytes of every occurrence of the attribute from Message, in wire order.
+    pub fn get_raw_attr_values(&self, attr: Attribute) -> Vec<Vec<u8>> {
+        self.attributes
+            .iter()
+            .filter(|(k, _)| *k == attr)
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    /// Get all attributes from Message in wire order.
+    pub fn get_raw_attrs(&self) -> &[(Attribute, Vec<u8>)] {
+        &self.attributes
+    }
+
+    /// Get the comprehension-required attributes of Message that are not understood by this library,
+    /// in wire order and without duplicates.
+    /// They are the attributes to be listed in UNKNOWN-ATTRIBUTES of a 420 Unknown Attribute response.
+    pub fn get_unknown_comprehension_required_attrs(&self) -> Vec<Attribute> {
+        self.get_unknown_comprehension_required_attrs_except(&[])
+    }
+
+    /// Same as [`Message::get_unknown_comprehension_required_attrs`], but the RFC3489 attributes
+    /// RESPONSE-ADDRESS, SOURCE-ADDRESS, CHANGED-ADDRESS, PASSWORD and REFLECTED-FROM are treated as understood.
+    /// RFC3489 servers always send SOURCE-ADDRESS and CHANGED-ADDRESS.
+    pub fn get_unknown_comprehension_required_attrs_lenient(&self) -> Vec<Attribute> {
+        self.get_unknown_comprehension_required_attrs_except(&[
+            ATTR_RESPONSE_ADDRESS,
+            ATTR_SOURCE_ADDRESS,
+            ATTR_CHANGED_ADDRESS,
+            ATTR_PASSWORD,
+            ATTR_REFLECTED_FROM,
+        ])
+    }
+
+    /// Same as [`Message::get_unknown_comprehension_required_attrs`], but the given attribute types
+    /// are treated as understood, e.g. the attributes of a STUN usage such as ICE handled by the caller.
+    pub fn get_unknown_comprehension_required_attrs_except(
+        &self,
+        understood: &[u16],
+    ) -> Vec<Attribute> {
+        let mut attrs = vec![];
+        for (attr, _) in self.attributes.iter() {
+            if let Attribute::Unknown(attribute) = attr {
+                if attr.is_comprehension_required()
+                    && !understood.contains(attribute)
+                    && !attrs.contains(attr)
+                {
+                    attrs.push(*attr);
+                }
+            }
+        }
+        attrs
+    }
+
+    /// Get the typed value of the attribute from Message.
+    /// If the attribute appears more than once, the first one is returned.
+    pub fn get_attr_value(
+        &self,
+        attr: Attribute,
+    ) -> Option<Result<AttributeValue, STUNClientError>> {
+        self.attributes
+            .iter()
+            .find(|(k, _)| *k == attr)
+            .map(|(k, v)| AttributeValue::decode(*k, v, &self.header.transaction_id))
+    }
+
+    /// Get the typed values of all attributes from Message in wire order.
+    pub fn get_attr_values(&self) -> Vec<Result<AttributeValue, STUNClientError>> {
+        self.attributes
+            .iter()
+            .map(|(k, v)| AttributeValue::decode(*k, v, &self.header.transaction_id))
+            .collect()
+    }
+
+    /// Appends an attribute to Message.
+    /// MESSAGE-INTEGRITY and FINGERPRINT should be added with their dedicated methods after all other attributes.
+    /// An existing MESSAGE-INTEGRITY, MESSAGE-INTEGRITY-SHA256 or FINGERPRINT is removed because it would no longer be valid.
+    /// Fails if the value does not fit in the attribute length, or if the message would not fit in the message length
+    /// with room left for MESSAGE-INTEGRITY, MESSAGE-INTEGRITY-SHA256 and FINGERPRINT.
+    pub fn add(&mut self, value: AttributeValue) -> Result<(), STUNClientError> {
+        let attr = value.attribute();
+        let raw_value = value.encode(&self.header.transaction_id)?;
+        if raw_value.len() > MAX_LENGTH {
+            return Err(STUNClientError::InvalidAttributeLengthError(
+                attr,
+                raw_value.len(),
+            ));
+        }
+        let length = self.encoded_len() - HEADER_BYTE_SIZE + padded_attr_len(raw_value.len());
+        if length + MAX_TRAILERS_BYTE_SIZE > MAX_LENGTH {
+            return Err(STUNClientError::MessageTooLargeError(length));
+        }
+
+        self.attributes.push((attr, raw_value));
+        self.remove_stale_trailers();
+        self.raw = None;
+        self.update_length();
+        Ok(())
     }
 
     /// Get the transaction id from Message.
-    pub fn get_transaction_id(&self) -> Vec<u8> {
-        self.header.transaction_id.clone()
+    pub fn get_transaction_id(&self) -> TransactionId {
+        self.header.transaction_id
     }
 
-    fn decode_attrs(attrs_buf: &[u8]) -> Result<HashMap<Attribute, Vec<u8>>, STUNClientError> {
-        let mut attrs_buf = attrs_buf.to_vec();
-        let mut attributes = HashMap::new();
+    /// Adds the MESSAGE-INTEGRITY attribute computed with the given key.
+    /// For short-term credentials the key is the password; for long-term credentials see RFC8489 Section 9.2.2.
+    /// This must be called after all other attributes have been added.
+    /// An existing MESSAGE-INTEGRITY-SHA256 or FINGERPRINT is removed because it would no longer be valid.
+    pub fn add_message_integrity(&mut self, key: &[u8]) {
+        self.remove_attrs(&[
+            Attribute::MessageIntegrity,
+            Attribute::MessageIntegritySHA256,
+            Attribute::Fingerprint,
+        ]);
 
-        if attrs_buf.is_empty() {
-            return Err(STUNClientError::ParseError());
+        let mac = hmac_sha1(key, &self.to_raw());
+        self.attributes.push((Attribute::MessageIntegrity, mac));
+        self.raw = None;
+        self.update_length();
+    }
+
+    /// Adds the MESSAGE-INTEGRITY-SHA256 attribute computed with the given key.
+    /// This must be called after all other attributes, including MESSAGE-INTEGRITY, have been added.
+    /// An existing FINGERPRINT is removed because it would no longer be valid.
+    pub fn add_message_integrity_sha256(&mut self, key: &[u8]) {
+        self.insert_message_integrity_sha256(key, MESSAGE_INTEGRITY_SHA256_BYTE_SIZE);
+    }
+
+    /// Adds the MESSAGE-INTEGRITY-SHA256 attribute truncated to the given length.
+    /// RFC8489: The length MUST be a multiple of 4 and at least 16 bytes.
+    pub fn add_truncated_message_integrity_sha256(
+        &mut self,
+        key: &[u8],
+        length: usize,
+    ) -> Result<(), STUNClientError> {
+        if !is_valid_message_integrity_sha256_length(length) {
+            return Err(STUNClientError::Unknown(format!(
+                "invalid MESSAGE-INTEGRITY-SHA256 length: {}",
+                length
+            )));
         }
 
-        while !attrs_buf.is_empty() {
-            if attrs_buf.len() < 4 {
-                break;
-            }
+        self.insert_message_integrity_sha256(key, length);
+        Ok(())
+    }
 
-            let attribute_type = Attribute::from_u16(u16::from_be_bytes([
-                attrs_buf.remove(0),
-                attrs_buf.remove(0),
-            ]));
-            let length =
-                u16::from_be_bytes([attrs_buf.remove(0), attrs_buf.remove(0)]) as usize;
-            if attrs_buf.len() < length {
-                return Err(STUNClientError::ParseError());
-            }
+    /// Verifies the message integrity with the given key.
+    /// MESSAGE-INTEGRITY-SHA256 is used if present, otherwise MESSAGE-INTEGRITY.
+    /// A message with neither fails with MissingIntegrityError.
+    /// Messages created by from_raw are verified against the bytes they were decoded from.
+    pub fn verify_integrity(&self, key: &[u8]) -> Result<(), STUNClientError> {
+        match &self.raw {
+            Some(raw) => verify_integrity(raw, key),
+            None => verify_integrity(&self.to_raw(), key),
+        }
+    }
+
+    /// Adds the FINGERPRINT attribute.
+    /// This must be called last, after MESSAGE-INTEGRITY and MESSAGE-INTEGRITY-SHA256.
+    pub fn add_fingerprint(&mut self) {
+        self.remove_attrs(&[Attribute::Fingerprint]);
+        let value = fingerprint(&self.to_raw());
+        self.attributes
+            .push((Attribute::Fingerprint, value.to_vec()));
+        self.raw = None;
+        self.update_length();
+    }
+
+    fn insert_message_integrity_sha256(&mut self, key: &[u8], length: usize) {
+        self.remove_attrs(&[Attribute::MessageIntegritySHA256, Attribute::Fingerprint]);
 
-            let value: Vec<u8> = attrs_buf.drain(..length).collect();
-            attributes.insert(attribute_type, value);
+        let mut mac = hmac_sha256(key, &self.to_raw(), length);
+        mac.truncate(length);
+        self.attributes
+            .push((Attribute::MessageIntegritySHA256, mac));
+        self.raw = None;
+        self.update_length();
+    }
+
+    // Removes the trailers if another attribute follows them, as their values do not cover it.
+    fn remove_stale_trailers(&mut self) {
+        let first_trailer = self
+            .attributes
+            .iter()
+            .position(|(k, _)| trailer_rank(*k) > 0);
+        if let Some(first_trailer) = first_trailer {
+            if self.attributes[first_trailer..]
+                .iter()
+                .any(|(k, _)| trailer_rank(*k) == 0)
+            {
+                self.remove_attrs(&[
+                    Attribute::MessageIntegrity,
+                    Attribute::MessageIntegritySHA256,
+                    Attribute::Fingerprint,
+                ]);
+            }
         }
+    }
+
+    fn remove_attrs(&mut self, attrs: &[Attribute]) {
+        self.attributes.retain(|(k, _)| !attrs.contains(k));
+        self.raw = None;
+    }
+
+    // The length is checked when the message is encoded.
+    fn update_length(&mut self) {
+        self.header.length = (self.encoded_len() - HEADER_BYTE_SIZE) as u16;
+    }
+}
+
+/// STUN transaction ID.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct TransactionId(pub [u8; TRANSACTION_ID_BYTE_SIZE]);
+
+impl TransactionId {
+    /// Generates a random transaction ID.
+    /// RFC8489: It is drawn from a cryptographically strong random number generator
+    /// so that off-path attackers cannot guess it and forge responses.
+    pub fn random() -> Self {
+        let mut id = [0; TRANSACTION_ID_BYTE_SIZE];
+        OsRng.fill_bytes(&mut id);
+        TransactionId(id)
+    }
+
+    /// Get the raw bytes of the transaction ID.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    // Reads the transaction ID from the header of raw message bytes of at least HEADER_BYTE_SIZE.
+    fn from_raw(buf: &[u8]) -> Self {
+        let mut id = [0; TRANSACTION_ID_BYTE_SIZE];
+        id.copy_from_slice(&buf[8..HEADER_BYTE_SIZE]);
+        TransactionId(id)
+    }
+}
 
-        Ok(attributes)
+impl From<[u8; TRANSACTION_ID_BYTE_SIZE]> for TransactionId {
+    fn from(id: [u8; TRANSACTION_ID_BYTE_SIZE]) -> Self {
+        TransactionId(id)
     }
 }
 
 /// Struct representing STUN header
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Header {
     method: Method,
     class: Class,
     length: u16,
-    transaction_id: Vec<u8>,
+    transaction_id: TransactionId,
 }
 
 impl Header {
     /// Create a STUN header.
-    pub fn new(method: Method, class: Class, length: u16, transaction_id: Vec<u8>) -> Header {
+    pub fn new(method: Method, class: Class, length: u16, transaction_id: TransactionId) -> Header {
         Header {
-            class: class,
-            method: method,
-            length: length,
-            transaction_id: transaction_id,
+            class,
+            method,
+            length,
+            transaction_id,
         }
     }
 
-    /// Create a STUN header from raw bytes.
+    /// Create a STUN header from the raw bytes of a whole message.
+    ///
+    /// As described in RFC8489 Section 6.3, the message is rejected unless
+    /// the most significant 2 bits of the message type are zero,
+    /// the magic cookie is present and the message length is a multiple of 4
+    /// that matches the number of bytes following the header.
     pub fn from_raw(buf: &[u8]) -> Result<Header, STUNClientError> {
-        let mut buf = buf.to_vec();
+        Header::decode(buf, true)
+    }
+
+    /// Same as [`Header::from_raw`], but does not check the magic cookie.
+    /// RFC3489 servers use those 4 bytes as part of a 128-bit transaction ID.
+    pub fn from_raw_lenient(buf: &[u8]) -> Result<Header, STUNClientError> {
+        Header::decode(buf, false)
+    }
+
+    fn decode(buf: &[u8], check_magic_cookie: bool) -> Result<Header, STUNClientError> {
+        let message_type = Header::validate(buf, check_magic_cookie)?;
+        Ok(Header {
+            class: Header::decode_class(message_type),
+            method: Header::decode_method(message_type),
+            length: (buf.len() - HEADER_BYTE_SIZE) as u16,
+            transaction_id: TransactionId::from_raw(buf),
+        })
+    }
+
+    // Validates the header of a whole message and returns the message type.
+    fn validate(buf: &[u8], check_magic_cookie: bool) -> Result<u16, STUNClientError> {
         if buf.len() < HEADER_BYTE_SIZE {
             return Err(STUNClientError::ParseError());
         }
 
-        let message_type = u16::from_be_bytes([buf.remove(0), buf.remove(0)]);
-        let class = Header::decode_class(message_type);
-        let method = Header::decode_method(message_type);
-        let length = u16::from_be_bytes([buf.remove(0), buf.remove(0)]);
+        let message_type = u16::from_be_bytes([buf[0], buf[1]]);
+        if message_type & 0xC000 != 0 {
+            return Err(STUNClientError::InvalidMessageTypeError(message_type));
+        }
 
-        Ok(Header {
-            class: class,
-            method: method,
-            length: length,
-            // 0..3 is Magic Cookie
-            transaction_id: buf[4..].to_vec(),
-        })
+        let length = u16::from_be_bytes([buf[2], buf[3]]);
+        if length % 4 != 0 {
+            return Err(STUNClientError::UnalignedLengthError(length));
+        }
+        if length as usize != buf.len() - HEADER_BYTE_SIZE {
+            return Err(STUNClientError::LengthMismatchError(
+                length,
+                buf.len() - HEADER_BYTE_SIZE,
+            ));
+        }
+
+        let magic_cookie = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if check_magic_cookie && magic_cookie != MAGIC_COOKIE {
+            return Err(STUNClientError::MagicCookieMismatchError(magic_cookie));
+        }
+
+        Ok(message_type)
     }
 
     /// Converts a Header to a STUN protocol header raw bytes.
@@ -411,17 +1379,27 @@ impl Header {
         bytes.extend(&message_type.to_be_bytes());
         bytes.extend(&self.length.to_be_bytes());
         bytes.extend(&MAGIC_COOKIE.to_be_bytes());
-        bytes.extend(&self.transaction_id);
+        bytes.extend(self.transaction_id.as_bytes());
         bytes
     }
 
     fn message_type(&self) -> u16 {
-        self.class.to_u16() | self.method.to_u16()
+        // RFC8489: The class bits C1 and C0 are interleaved with the 12-bit method as
+        // M11-M7 C1 M6-M4 C0 M3-M0.
+        let method = self.method.to_u16();
+        self.class.to_u16()
+            | ((method & 0x0F80) << 2)
+            | ((method & 0x0070) << 1)
+            | (method & 0x000F)
     }
 
     fn decode_method(message_type: u16) -> Method {
         // RFC8489: M11 through M0 represent a 12-bit encoding of the method
-        Method::from_u16(message_type & 0x3EEF)
+        Method::from_u16(
+            ((message_type & 0x3E00) >> 2)
+                | ((message_type & 0x00E0) >> 1)
+                | (message_type & 0x000F),
+        )
     }
 
     fn decode_class(message_type: u16) -> Class {
@@ -430,27 +1408,530 @@ impl Header {
     }
 }
 
-fn bytes_to_ip_addr(family: u8, b: Vec<u8>) -> Option<IpAddr> {
-    match family {
-        FAMILY_IPV4 => Some(IpAddr::V4(Ipv4Addr::from([b[0], b[1], b[2], b[3]]))),
-        FAMILY_IPV6 => Some(IpAddr::V6(Ipv6Addr::from([
-            b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11], b[12], b[13],
-            b[14], b[15],
-        ]))),
-        _ => None,
-    }
+/// Builder of STUN messages.
+///
+/// Unlike [`Message::new`], the transaction ID can be supplied by the caller,
+/// e.g. to answer a request or to retransmit a request with the same ID.
+/// Attributes are encoded in the order they are added, and MESSAGE-INTEGRITY,
+/// MESSAGE-INTEGRITY-SHA256 and FINGERPRINT are always placed after them in that order.
+#[derive(Clone, Debug)]
+pub struct MessageBuilder {
+    method: Method,
+    class: Class,
+    transaction_id: Option<TransactionId>,
+    attributes: Vec<AttributeValue>,
+    message_integrity: Option<Vec<u8>>,
+    message_integrity_sha256: Option<(Vec<u8>, usize)>,
+    fingerprint: bool,
 }
 
-/// An enum that defines the type of STUN error code.
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
-pub enum ErrorCode {
-    TryAlternate(String),
-    BadRequest(String),
-    Unauthorized(String),
-    UnknownAttribute(String),
+impl MessageBuilder {
+    /// Create a builder of a message with the method and class.
+    pub fn new(method: Method, class: Class) -> MessageBuilder {
+        MessageBuilder {
+            method,
+            class,
+            transaction_id: None,
+            attributes: vec![],
+            message_integrity: None,
+            message_integrity_sha256: None,
+            fingerprint: false,
+        }
+    }
+
+    /// Sets the transaction ID. A random one is generated if it is not set.
+    pub fn transaction_id(mut self, transaction_id: TransactionId) -> Self {
+        self.transaction_id = Some(transaction_id);
+        self
+    }
+
+    /// Appends an attribute.
+    pub fn attr(mut self, value: AttributeValue) -> Self {
+        self.attributes.push(value);
+        self
+    }
+
+    /// Adds MESSAGE-INTEGRITY computed with the key.
+    pub fn message_integrity(mut self, key: &[u8]) -> Self {
+        self.message_integrity = Some(key.to_vec());
+        self
+    }
+
+    /// Adds MESSAGE-INTEGRITY-SHA256 computed with the key and truncated to the length.
+    pub fn message_integrity_sha256(mut self, key: &[u8], length: usize) -> Self {
+        self.message_integrity_sha256 = Some((key.to_vec(), length));
+        self
+    }
+
+    /// Adds FINGERPRINT.
+    pub fn fingerprint(mut self) -> Self {
+        self.fingerprint = true;
+        self
+    }
+
+    /// Builds the Message.
+    pub fn build(&self) -> Result<Message, STUNClientError> {
+        let transaction_id = self.transaction_id.unwrap_or_else(TransactionId::random);
+        let mut msg = Message {
+            header: Header::new(self.method, self.class, 0, transaction_id),
+            attributes: vec![],
+            raw: None,
+        };
+        for value in self.attributes.iter() {
+            msg.add(value.clone())?;
+        }
+        if let Some(key) = &self.message_integrity {
+            msg.add_message_integrity(key);
+        }
+        if let Some((key, length)) = &self.message_integrity_sha256 {
+            msg.add_truncated_message_integrity_sha256(key, *length)?;
+        }
+        if self.fingerprint {
+            msg.add_fingerprint();
+        }
+
+        Ok(msg)
+    }
+
+    /// Encodes the message into the buffer and returns the number of bytes written.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, STUNClientError> {
+        self.build()?.encode_into(buf)
+    }
+
+    /// Appends the encoded message to the buffer and returns the number of bytes written.
+    pub fn encode_into_bytes(&self, buf: &mut BytesMut) -> Result<usize, STUNClientError> {
+        Ok(self.build()?.encode_into_bytes(buf))
+    }
+}
+
+/// A borrowed view of a STUN message.
+/// The message is validated once when the view is created, in the same way as [`Message::from_raw`],
+/// and attribute values are returned as slices of the buffer without allocating.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MessageRef<'a> {
+    buf: &'a [u8],
+    method: Method,
+    class: Class,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Create a view of the STUN message in the raw bytes.
+    pub fn from_raw(buf: &'a [u8]) -> Result<Self, STUNClientError> {
+        MessageRef::decode(buf, true)
+    }
+
+    /// Create a view of the STUN message in the raw bytes without checking the magic cookie.
+    pub fn from_raw_lenient(buf: &'a [u8]) -> Result<Self, STUNClientError> {
+        MessageRef::decode(buf, false)
+    }
+
+    fn decode(buf: &'a [u8], check_magic_cookie: bool) -> Result<Self, STUNClientError> {
+        let message_type = Header::validate(buf, check_magic_cookie)?;
+        verify_fingerprint(buf)?;
+
+        let mut rest = &buf[HEADER_BYTE_SIZE..];
+        let mut after_fingerprint = false;
+        while rest.len() >= ATTR_HEADER_BYTE_SIZE {
+            let attr = Attribute::from_u16(u16::from_be_bytes([rest[0], rest[1]]));
+            let length = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+            if rest.len() - ATTR_HEADER_BYTE_SIZE < length {
+                return Err(STUNClientError::InvalidAttributeLengthError(attr, length));
+            }
+
+            // RFC8489: The FINGERPRINT attribute MUST be the last attribute in the message.
+            if after_fingerprint {
+                return Err(STUNClientError::ParseError());
+            }
+            after_fingerprint = attr == Attribute::Fingerprint;
+
+            // RFC8489: The padding bits MUST be ignored.
+            let next = ATTR_HEADER_BYTE_SIZE + length + padding_len(length);
+            rest = &rest[next.min(rest.len())..];
+        }
+
+        Ok(MessageRef {
+            buf,
+            method: Header::decode_method(message_type),
+            class: Header::decode_class(message_type),
+        })
+    }
+
+    /// Get the method from the message.
+    pub fn get_method(&self) -> Method {
+        self.method
+    }
+
+    /// Get the class from the message.
+    pub fn get_class(&self) -> Class {
+        self.class
+    }
+
+    /// Get the transaction id from the message.
+    pub fn get_transaction_id(&self) -> TransactionId {
+        TransactionId::from_raw(self.buf)
+    }
+
+    /// Get the raw bytes of the whole message.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// Get an iterator over the attributes of the message in wire order.
+    pub fn attrs(&self) -> AttributeIter<'a> {
+        AttributeIter {
+            rest: &self.buf[HEADER_BYTE_SIZE..],
+            last_trailer_rank: 0,
+        }
+    }
+
+    /// Get the raw attribute bytes from the message.
+    /// If the attribute appears more than once, the first one is returned.
+    pub fn get_raw_attr_value(&self, attr: Attribute) -> Option<&'a [u8]> {
+        self.attrs().find(|(k, _)| *k == attr).map(|(_, v)| v)
+    }
+
+    /// Get the typed value of the attribute from the message.
+    /// If the attribute appears more than once, the first one is returned.
+    pub fn get_attr_value(
+        &self,
+        attr: Attribute,
+    ) -> Option<Result<AttributeValue, STUNClientError>> {
+        self.get_raw_attr_value(attr)
+            .map(|v| AttributeValue::decode(attr, v, &self.get_transaction_id()))
+    }
+
+    /// Verifies the message integrity with the given key, see [`Message::verify_integrity`].
+    pub fn verify_integrity(&self, key: &[u8]) -> Result<(), STUNClientError> {
+        verify_integrity(self.buf, key)
+    }
+
+    /// Copies the message into an owned Message.
+    pub fn to_message(&self) -> Message {
+        Message {
+            header: Header::new(
+                self.method,
+                self.class,
+                (self.buf.len() - HEADER_BYTE_SIZE) as u16,
+                self.get_transaction_id(),
+            ),
+            attributes: self.attrs().map(|(k, v)| (k, v.to_vec())).collect(),
+            raw: Some(self.buf.to_vec()),
+        }
+    }
+}
+
+/// An iterator over the attributes of a [`MessageRef`].
+/// RFC8489: Attributes following MESSAGE-INTEGRITY other than MESSAGE-INTEGRITY-SHA256 and FINGERPRINT are skipped.
+#[derive(Clone, Debug)]
+pub struct AttributeIter<'a> {
+    rest: &'a [u8],
+    last_trailer_rank: u8,
+}
+
+impl<'a> Iterator for AttributeIter<'a> {
+    type Item = (Attribute, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.rest.len() >= ATTR_HEADER_BYTE_SIZE {
+            let attr = Attribute::from_u16(u16::from_be_bytes([self.rest[0], self.rest[1]]));
+            let length = u16::from_be_bytes([self.rest[2], self.rest[3]]) as usize;
+            let value = self
+                .rest
+                .get(ATTR_HEADER_BYTE_SIZE..ATTR_HEADER_BYTE_SIZE + length)?;
+            let next = ATTR_HEADER_BYTE_SIZE + length + padding_len(length);
+            self.rest = &self.rest[next.min(self.rest.len())..];
+
+            let rank = trailer_rank(attr);
+            if self.last_trailer_rank > 0 && rank <= self.last_trailer_rank {
+                continue;
+            }
+            self.last_trailer_rank = rank;
+            return Some((attr, value));
+        }
+
+        None
+    }
+}
+
+impl PartialEq for Message {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header && self.attributes == other.attributes
+    }
+}
+
+// Returns the offset of the first attribute of the given type in raw message bytes.
+fn find_attr_offset(buf: &[u8], attr: Attribute) -> Option<usize> {
+    let mut offset = HEADER_BYTE_SIZE;
+    while offset + ATTR_HEADER_BYTE_SIZE <= buf.len() {
+        let attr_type = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+        if attr_type == attr.to_u16() {
+            return Some(offset);
+        }
+
+        let length = u16::from_be_bytes([buf[offset + 2], buf[offset + 3]]) as usize;
+        offset += ATTR_HEADER_BYTE_SIZE + length + padding_len(length);
+    }
+
+    None
+}
+
+// The size of MESSAGE-INTEGRITY, MESSAGE-INTEGRITY-SHA256 and FINGERPRINT together, which add leaves room for.
+const MAX_TRAILERS_BYTE_SIZE: usize = 3 * ATTR_HEADER_BYTE_SIZE
+    + MESSAGE_INTEGRITY_BYTE_SIZE
+    + MESSAGE_INTEGRITY_SHA256_BYTE_SIZE
+    + FINGERPRINT_BYTE_SIZE;
+
+// The attributes that must be placed at the end of a message, ranked by the order they appear in.
+// RFC8489: MESSAGE-INTEGRITY is followed only by MESSAGE-INTEGRITY-SHA256 and FINGERPRINT, and FINGERPRINT is the last attribute.
+fn trailer_rank(attr: Attribute) -> u8 {
+    match attr {
+        Attribute::MessageIntegrity => 1,
+        Attribute::MessageIntegritySHA256 => 2,
+        Attribute::Fingerprint => 3,
+        _ => 0,
+    }
+}
+
+// RFC8489: Each STUN attribute MUST end on a 32-bit boundary.
+fn padding_len(length: usize) -> usize {
+    (4 - length % 4) % 4
+}
+
+// The encoded size of an attribute with a value of the given length, including its header and padding.
+fn padded_attr_len(length: usize) -> usize {
+    ATTR_HEADER_BYTE_SIZE + length + padding_len(length)
+}
+
+// RFC8489: The text used as input to HMAC is the STUN message, up to and including the attribute preceding the MESSAGE-INTEGRITY attribute.
+// The Length field of the STUN message header is adjusted to point to the end of the MESSAGE-INTEGRITY attribute.
+fn update_with_length(buf: &[u8], mac_len: usize, mut update: impl FnMut(&[u8])) {
+    let length = (buf.len() - HEADER_BYTE_SIZE + ATTR_HEADER_BYTE_SIZE + mac_len) as u16;
+    update(&buf[..2]);
+    update(&length.to_be_bytes());
+    update(&buf[4..]);
+}
+
+// Returns the value of the attribute at the given offset in raw message bytes.
+fn attr_value_at(buf: &[u8], offset: usize) -> Option<&[u8]> {
+    let length = u16::from_be_bytes([*buf.get(offset + 2)?, *buf.get(offset + 3)?]) as usize;
+    let value_offset = offset + ATTR_HEADER_BYTE_SIZE;
+    buf.get(value_offset..value_offset + length)
+}
+
+fn is_valid_message_integrity_sha256_length(length: usize) -> bool {
+    (MIN_MESSAGE_INTEGRITY_SHA256_BYTE_SIZE..=MESSAGE_INTEGRITY_SHA256_BYTE_SIZE).contains(&length)
+        && length % 4 == 0
+}
+
+// HMAC accepts keys of any length, so creating the MACs below never fails.
+fn hmac_sha1(key: &[u8], buf: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(key).expect("HMAC can take key of any size");
+    update_with_length(buf, MESSAGE_INTEGRITY_BYTE_SIZE, |b| mac.update(b));
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hmac_sha256(key: &[u8], buf: &[u8], mac_len: usize) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+    update_with_length(buf, mac_len, |b| mac.update(b));
+    mac.finalize().into_bytes().to_vec()
+}
+
+// RFC8489: The value of the attribute is computed as the CRC-32 of the STUN message up to (but excluding) the FINGERPRINT attribute itself, XOR'ed with the 32-bit value 0x5354554e.
+fn fingerprint(buf: &[u8]) -> [u8; FINGERPRINT_BYTE_SIZE] {
+    let mut hasher = Hasher::new();
+    update_with_length(buf, FINGERPRINT_BYTE_SIZE, |b| hasher.update(b));
+    (hasher.finalize() ^ FINGERPRINT_XOR_VALUE).to_be_bytes()
+}
+
+// Verifies FINGERPRINT of raw message bytes if present.
+fn verify_fingerprint(buf: &[u8]) -> Result<(), STUNClientError> {
+    if let Some(offset) = find_attr_offset(buf, Attribute::Fingerprint) {
+        let value = attr_value_at(buf, offset).ok_or(STUNClientError::ParseError())?;
+        if value != fingerprint(&buf[..offset]) {
+            return Err(STUNClientError::FingerprintMismatchError());
+        }
+    }
+    Ok(())
+}
+
+// Verifies MESSAGE-INTEGRITY-SHA256 if present, otherwise MESSAGE-INTEGRITY, of raw message bytes.
+fn verify_integrity(raw: &[u8], key: &[u8]) -> Result<(), STUNClientError> {
+    if let Some(offset) = find_attr_offset(raw, Attribute::MessageIntegritySHA256) {
+        let value = attr_value_at(raw, offset).ok_or(STUNClientError::ParseError())?;
+        if !is_valid_message_integrity_sha256_length(value.len()) {
+            return Err(STUNClientError::InvalidAttributeLengthError(
+                Attribute::MessageIntegritySHA256,
+                value.len(),
+            ));
+        }
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key)
+            .map_err(|e| STUNClientError::Unknown(e.to_string()))?;
+        update_with_length(&raw[..offset], value.len(), |b| mac.update(b));
+        return mac
+            .verify_truncated_left(value)
+            .map_err(|_| STUNClientError::IntegrityMismatchError());
+    }
+
+    let offset = find_attr_offset(raw, Attribute::MessageIntegrity)
+        .ok_or(STUNClientError::MissingIntegrityError())?;
+    let value = attr_value_at(raw, offset).ok_or(STUNClientError::ParseError())?;
+    if value.len() != MESSAGE_INTEGRITY_BYTE_SIZE {
+        return Err(STUNClientError::InvalidAttributeLengthError(
+            Attribute::MessageIntegrity,
+            value.len(),
+        ));
+    }
+
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(key).map_err(|e| STUNClientError::Unknown(e.to_string()))?;
+    update_with_length(&raw[..offset], value.len(), |b| mac.update(b));
+    mac.verify_slice(value)
+        .map_err(|_| STUNClientError::IntegrityMismatchError())
+}
+
+fn encode_address(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = vec![0];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes.push(FAMILY_IPV4);
+            bytes.extend(&addr.port().to_be_bytes());
+            bytes.extend(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(FAMILY_IPV6);
+            bytes.extend(&addr.port().to_be_bytes());
+            bytes.extend(&ip.octets());
+        }
+    }
+    bytes
+}
+
+fn decode_address(attr: Attribute, value: &[u8]) -> Result<SocketAddr, STUNClientError> {
+    let head = value
+        .get(..4)
+        .ok_or(STUNClientError::InvalidAttributeLengthError(
+            attr,
+            value.len(),
+        ))?;
+    let family = head[1];
+    let port = u16::from_be_bytes([head[2], head[3]]);
+    let ip_addr = match family {
+        FAMILY_IPV4 | FAMILY_IPV6 => bytes_to_ip_addr(family, &value[4..]).ok_or(
+            STUNClientError::InvalidAttributeLengthError(attr, value.len()),
+        )?,
+        _ => {
+            return Err(STUNClientError::InvalidAttributeValueError(
+                attr,
+                format!("unknown address family {:#04x}", family),
+            ))
+        }
+    };
+    Ok(SocketAddr::new(ip_addr, port))
+}
+
+// Prepares a string with SASLprep, which RFC8489 keeps for compatibility with the OpaqueString profile.
+fn opaque_string(attr: Attribute, s: &str) -> Result<String, STUNClientError> {
+    stringprep::saslprep(s)
+        .map(|s| s.into_owned())
+        .map_err(|e| STUNClientError::InvalidAttributeValueError(attr, e.to_string()))
+}
+
+// Values must be shorter than these limits when they are sent.
+fn encoded_len_limit(attr: Attribute) -> Option<usize> {
+    match attr {
+        Attribute::Username => Some(MAX_USERNAME_BYTE_SIZE),
+        Attribute::Realm => Some(MAX_REALM_BYTE_SIZE),
+        Attribute::Nonce => Some(MAX_NONCE_BYTE_SIZE),
+        Attribute::AlternateDomain => Some(MAX_ALTERNATE_DOMAIN_BYTE_SIZE + 1),
+        _ => None,
+    }
+}
+
+// Received values must be shorter than these limits, which tolerate other encodings of the same characters.
+fn decoded_len_limit(attr: Attribute) -> Option<usize> {
+    match attr {
+        Attribute::Username => Some(MAX_RECEIVED_USERNAME_BYTE_SIZE),
+        Attribute::Realm | Attribute::Nonce => Some(MAX_RECEIVED_REALM_NONCE_BYTE_SIZE + 1),
+        Attribute::AlternateDomain => Some(MAX_ALTERNATE_DOMAIN_BYTE_SIZE + 1),
+        _ => None,
+    }
+}
+
+fn fixed_size<const N: usize>(attr: Attribute, value: &[u8]) -> Result<[u8; N], STUNClientError> {
+    <[u8; N]>::try_from(value)
+        .map_err(|_| STUNClientError::InvalidAttributeLengthError(attr, value.len()))
+}
+
+// Converts between the address attribute encoding and the XOR'ed encoding; the operation is its own inverse.
+fn xor_address(value: &[u8], transaction_id: &TransactionId) -> Vec<u8> {
+    // RFC8489: X-Port is computed by XOR'ing the mapped port with the most significant 16 bits of the magic cookie.
+    // If the IP address family is IPv4, X-Address is computed by XOR'ing the mapped IP address with the magic cookie.
+    // If the IP address family is IPv6, X-Address is computed by XOR'ing the mapped IP address with the concatenation of the magic cookie and the 96-bit transaction ID.
+    let mut mask = vec![0, 0];
+    mask.extend(&MAGIC_COOKIE.to_be_bytes()[..2]);
+    mask.extend(&MAGIC_COOKIE.to_be_bytes());
+    mask.extend(transaction_id.as_bytes());
+    value
+        .iter()
+        .zip(mask.iter().chain(std::iter::repeat(&0)))
+        .map(|(b, m)| b ^ m)
+        .collect()
+}
+
+fn bytes_to_ip_addr(family: u8, b: &[u8]) -> Option<IpAddr> {
+    match family {
+        FAMILY_IPV4 => <[u8; 4]>::try_from(b)
+            .ok()
+            .map(|b| IpAddr::V4(Ipv4Addr::from(b))),
+        FAMILY_IPV6 => <[u8; 16]>::try_from(b)
+            .ok()
+            .map(|b| IpAddr::V6(Ipv6Addr::from(b))),
+        _ => None,
+    }
+}
+
+/// An enum that defines the type of STUN error code.
+/// The codes are registered in the IANA STUN Error Codes registry by RFC8489, RFC8656, RFC6062 and RFC8445.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum ErrorCode {
+    /// 300 Try Alternate
+    TryAlternate(String),
+    /// 400 Bad Request
+    BadRequest(String),
+    /// 401 Unauthorized
+    Unauthorized(String),
+    /// 403 Forbidden
+    Forbidden(String),
+    /// 405 Mobility Forbidden
+    MobilityForbidden(String),
+    /// 420 Unknown Attribute
+    UnknownAttribute(String),
+    /// 437 Allocation Mismatch
+    AllocationMismatch(String),
+    /// 438 Stale Nonce
     StaleNonce(String),
+    /// 440 Address Family not Supported
+    AddressFamilyNotSupported(String),
+    /// 441 Wrong Credentials
+    WrongCredentials(String),
+    /// 442 Unsupported Transport Protocol
+    UnsupportedTransportProtocol(String),
+    /// 443 Peer Address Family Mismatch
+    PeerAddressFamilyMismatch(String),
+    /// 446 Connection Already Exists
+    ConnectionAlreadyExists(String),
+    /// 447 Connection Timeout or Failure
+    ConnectionTimeoutOrFailure(String),
+    /// 486 Allocation Quota Reached
+    AllocationQuotaReached(String),
+    /// 487 Role Conflict
+    RoleConflict(String),
+    /// 500 Server Error
     ServerError(String),
-    Unknown(String),
+    /// 508 Insufficient Capacity
+    InsufficientCapacity(String),
+    Unknown(u16, String),
 }
 
 impl ErrorCode {
@@ -459,27 +1940,1005 @@ impl ErrorCode {
             300 => Self::TryAlternate(reason),
             400 => Self::BadRequest(reason),
             401 => Self::Unauthorized(reason),
+            403 => Self::Forbidden(reason),
+            405 => Self::MobilityForbidden(reason),
             420 => Self::UnknownAttribute(reason),
+            437 => Self::AllocationMismatch(reason),
             438 => Self::StaleNonce(reason),
+            440 => Self::AddressFamilyNotSupported(reason),
+            441 => Self::WrongCredentials(reason),
+            442 => Self::UnsupportedTransportProtocol(reason),
+            443 => Self::PeerAddressFamilyMismatch(reason),
+            446 => Self::ConnectionAlreadyExists(reason),
+            447 => Self::ConnectionTimeoutOrFailure(reason),
+            486 => Self::AllocationQuotaReached(reason),
+            487 => Self::RoleConflict(reason),
             500 => Self::ServerError(reason),
-            _ => Self::Unknown(reason),
+            508 => Self::InsufficientCapacity(reason),
+            _ => Self::Unknown(code, reason),
         }
     }
+
+    /// Gets the numeric error code.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::TryAlternate(_) => 300,
+            Self::BadRequest(_) => 400,
+            Self::Unauthorized(_) => 401,
+            Self::Forbidden(_) => 403,
+            Self::MobilityForbidden(_) => 405,
+            Self::UnknownAttribute(_) => 420,
+            Self::AllocationMismatch(_) => 437,
+            Self::StaleNonce(_) => 438,
+            Self::AddressFamilyNotSupported(_) => 440,
+            Self::WrongCredentials(_) => 441,
+            Self::UnsupportedTransportProtocol(_) => 442,
+            Self::PeerAddressFamilyMismatch(_) => 443,
+            Self::ConnectionAlreadyExists(_) => 446,
+            Self::ConnectionTimeoutOrFailure(_) => 447,
+            Self::AllocationQuotaReached(_) => 486,
+            Self::RoleConflict(_) => 487,
+            Self::ServerError(_) => 500,
+            Self::InsufficientCapacity(_) => 508,
+            Self::Unknown(code, _) => *code,
+        }
+    }
+
+    /// Gets the reason phrase.
+    pub fn reason(&self) -> &str {
+        match self {
+            Self::TryAlternate(reason)
+            | Self::BadRequest(reason)
+            | Self::Unauthorized(reason)
+            | Self::Forbidden(reason)
+            | Self::MobilityForbidden(reason)
+            | Self::UnknownAttribute(reason)
+            | Self::AllocationMismatch(reason)
+            | Self::StaleNonce(reason)
+            | Self::AddressFamilyNotSupported(reason)
+            | Self::WrongCredentials(reason)
+            | Self::UnsupportedTransportProtocol(reason)
+            | Self::PeerAddressFamilyMismatch(reason)
+            | Self::ConnectionAlreadyExists(reason)
+            | Self::ConnectionTimeoutOrFailure(reason)
+            | Self::AllocationQuotaReached(reason)
+            | Self::RoleConflict(reason)
+            | Self::ServerError(reason)
+            | Self::InsufficientCapacity(reason)
+            | Self::Unknown(_, reason) => reason,
+        }
+    }
+}
+
+fn encode_error_code(code: u16, reason: &str) -> Result<Vec<u8>, STUNClientError> {
+    if !is_valid_error_code(code) {
+        return Err(STUNClientError::InvalidAttributeValueError(
+            Attribute::ErrorCode,
+            format!("error code {} is out of range", code),
+        ));
+    }
+    if reason.chars().count() >= MAX_REASON_PHRASE_CHARS {
+        return Err(STUNClientError::InvalidAttributeValueError(
+            Attribute::ErrorCode,
+            format!(
+                "reason phrase must have fewer than {} characters",
+                MAX_REASON_PHRASE_CHARS
+            ),
+        ));
+    }
+    // RFC8489: The Class represents the hundreds digit of the error code and the Number represents the binary encoding of the error code modulo 100.
+    let mut bytes = vec![0, 0, (code / 100) as u8, (code % 100) as u8];
+    bytes.extend(reason.as_bytes());
+    Ok(bytes)
+}
+
+fn is_valid_error_code(code: u16) -> bool {
+    // RFC8489: The Class must be between 3 and 6 and the Number must be between 0 and 99.
+    (300..700).contains(&code)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn message_new_and_message_from_raw_are_equivalent() {
-        let mut attrs = HashMap::new();
-        attrs.insert(
+        let attrs = vec![(
             Attribute::ChangeRequest,
             Attribute::generate_change_request_value(true, false),
-        );
+        )];
         let msg = Message::new(Method::Binding, Class::Request, Some(attrs));
         let re_built_msg = Message::from_raw(&msg.to_raw()).unwrap();
         assert_eq!(msg, re_built_msg);
     }
+
+    // RFC5769 Section 2.2: Sample IPv4 Response
+    const SAMPLE_IPV4_RESPONSE: [u8; 80] = [
+        0x01, 0x01, 0x00, 0x3c, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x08, 0x00, 0x01, 0xa1, 0x47, 0xe1,
+        0x12, 0xa6, 0x43, 0x00, 0x08, 0x00, 0x14, 0x2b, 0x91, 0xf5, 0x99, 0xfd, 0x9e, 0x90, 0xc3,
+        0x8c, 0x74, 0x89, 0xf9, 0x2a, 0xf9, 0xba, 0x53, 0xf0, 0x6b, 0xe7, 0xd7, 0x80, 0x28, 0x00,
+        0x04, 0xc0, 0x7d, 0x4c, 0x96,
+    ];
+
+    // RFC5769 Section 2.3: Sample IPv6 Response
+    const SAMPLE_IPV6_RESPONSE: [u8; 92] = [
+        0x01, 0x01, 0x00, 0x48, 0x21, 0x12, 0xa4, 0x42, 0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6,
+        0x86, 0xfa, 0x87, 0xdf, 0xae, 0x80, 0x22, 0x00, 0x0b, 0x74, 0x65, 0x73, 0x74, 0x20, 0x76,
+        0x65, 0x63, 0x74, 0x6f, 0x72, 0x20, 0x00, 0x20, 0x00, 0x14, 0x00, 0x02, 0xa1, 0x47, 0x01,
+        0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25, 0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        0x00, 0x08, 0x00, 0x14, 0xa3, 0x82, 0x95, 0x4e, 0x4b, 0xe6, 0x7b, 0xf1, 0x17, 0x84, 0xc9,
+        0x7c, 0x82, 0x92, 0xc2, 0x75, 0xbf, 0xe3, 0xed, 0x41, 0x80, 0x28, 0x00, 0x04, 0xc8, 0xfb,
+        0x0b, 0x4c,
+    ];
+
+    const SAMPLE_PASSWORD: &[u8] = b"VOkJxbRl1RmTxUk/WvJxBt";
+
+    #[test]
+    fn sample_responses_with_padded_software_are_decoded() {
+        let msg = Message::from_raw(&SAMPLE_IPV4_RESPONSE).unwrap();
+        assert_eq!(Attribute::get_software(&msg).unwrap(), "test vector");
+        assert_eq!(
+            Attribute::get_xor_mapped_address(&msg).unwrap(),
+            "192.0.2.1:32853".parse().unwrap()
+        );
+        assert!(msg.verify_integrity(SAMPLE_PASSWORD).is_ok());
+
+        let msg = Message::from_raw(&SAMPLE_IPV6_RESPONSE).unwrap();
+        assert_eq!(Attribute::get_software(&msg).unwrap(), "test vector");
+        assert_eq!(
+            Attribute::get_xor_mapped_address(&msg).unwrap(),
+            "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                .parse()
+                .unwrap()
+        );
+        assert!(msg.verify_integrity(SAMPLE_PASSWORD).is_ok());
+    }
+
+    #[test]
+    fn message_ref_borrows_attribute_values() {
+        let msg_ref = MessageRef::from_raw(&SAMPLE_IPV6_RESPONSE).unwrap();
+        assert_eq!(msg_ref.get_class(), Class::SuccessResponse);
+        assert_eq!(
+            msg_ref.get_transaction_id().as_bytes(),
+            &SAMPLE_IPV6_RESPONSE[8..20]
+        );
+        assert_eq!(
+            msg_ref.get_raw_attr_value(Attribute::Software),
+            Some(&SAMPLE_IPV6_RESPONSE[24..35])
+        );
+        assert_eq!(
+            msg_ref
+                .get_attr_value(Attribute::XORMappedAddress)
+                .unwrap()
+                .unwrap(),
+            AttributeValue::XORMappedAddress(
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert!(msg_ref.verify_integrity(SAMPLE_PASSWORD).is_ok());
+
+        let attrs: Vec<Attribute> = msg_ref.attrs().map(|(k, _)| k).collect();
+        assert_eq!(
+            attrs,
+            vec![
+                Attribute::Software,
+                Attribute::XORMappedAddress,
+                Attribute::MessageIntegrity,
+                Attribute::Fingerprint
+            ]
+        );
+        assert_eq!(
+            msg_ref.to_message(),
+            Message::from_raw(&SAMPLE_IPV6_RESPONSE).unwrap()
+        );
+    }
+
+    #[test]
+    fn odd_length_attributes_are_padded() {
+        let attrs = vec![
+            (Attribute::Software, b"stun-client".to_vec()),
+            (Attribute::Username, b"user".to_vec()),
+        ];
+        let msg = Message::new(Method::Binding, Class::Request, Some(attrs));
+        let raw = msg.to_raw();
+        assert_eq!(raw.len(), HEADER_BYTE_SIZE + 8 + 16);
+        assert_eq!(u16::from_be_bytes([raw[2], raw[3]]) as usize, 8 + 16);
+        assert_eq!(Message::from_raw(&raw).unwrap(), msg);
+    }
+
+    #[test]
+    fn oversized_attributes_and_messages_are_rejected() {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        assert!(matches!(
+            msg.add(AttributeValue::Software("a".repeat(70000))),
+            Err(STUNClientError::InvalidAttributeLengthError(
+                Attribute::Software,
+                70000
+            ))
+        ));
+        assert!(msg.get_raw_attrs().is_empty());
+
+        // The trailers still fit after the largest attributes accepted.
+        msg.add(AttributeValue::Software("a".repeat(65000)))
+            .unwrap();
+        assert!(matches!(
+            msg.add(AttributeValue::Software("a".repeat(1000))),
+            Err(STUNClientError::MessageTooLargeError(_))
+        ));
+        msg.add_message_integrity(b"key");
+        msg.add_message_integrity_sha256(b"key");
+        msg.add_fingerprint();
+        let raw = msg.to_raw();
+        assert_eq!(Message::from_raw(&raw).unwrap(), msg);
+
+        let mut buf = vec![0; 140000];
+        let msg = Message::new(
+            Method::Binding,
+            Class::Request,
+            Some(vec![(Attribute::Software, vec![b'a'; 70000])]),
+        );
+        assert!(matches!(
+            msg.encode_into(&mut buf),
+            Err(STUNClientError::InvalidAttributeLengthError(
+                Attribute::Software,
+                70000
+            ))
+        ));
+        let msg = Message::new(
+            Method::Binding,
+            Class::Request,
+            Some(vec![
+                (Attribute::Software, vec![b'a'; 40000]),
+                (Attribute::Software, vec![b'a'; 40000]),
+            ]),
+        );
+        assert!(matches!(
+            msg.encode_into(&mut buf),
+            Err(STUNClientError::MessageTooLargeError(80008))
+        ));
+    }
+
+    #[test]
+    fn typed_attribute_values_round_trip() {
+        let values = vec![
+            AttributeValue::XORMappedAddress("192.0.2.1:32853".parse().unwrap()),
+            AttributeValue::XORMappedAddress(
+                "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+                    .parse()
+                    .unwrap(),
+            ),
+            AttributeValue::MappedAddress("198.51.100.2:3478".parse().unwrap()),
+            AttributeValue::Software(String::from("stun-client")),
+            AttributeValue::ErrorCode {
+                code: 420,
+                reason: String::from("Unknown Attribute"),
+            },
+            AttributeValue::ChangeRequest {
+                change_ip: true,
+                change_port: false,
+            },
+            AttributeValue::UnknownAttributes(vec![Attribute::Unknown(0x0030)]),
+            AttributeValue::Opaque(Attribute::Unknown(0x8030), vec![1, 2, 3]),
+        ];
+        let mut msg = Message::new(Method::Binding, Class::SuccessResponse, None);
+        for value in values.iter() {
+            msg.add(value.clone()).unwrap();
+        }
+
+        let re_built_msg = Message::from_raw(&msg.to_raw()).unwrap();
+        let decoded: Vec<AttributeValue> = re_built_msg
+            .get_attr_values()
+            .into_iter()
+            .map(|v| v.unwrap())
+            .collect();
+        assert_eq!(decoded, values);
+    }
+
+    fn arb_socket_addr() -> impl Strategy<Value = SocketAddr> {
+        prop_oneof![
+            (any::<[u8; 4]>(), any::<u16>())
+                .prop_map(|(ip, port)| SocketAddr::new(IpAddr::V4(Ipv4Addr::from(ip)), port)),
+            (any::<[u8; 16]>(), any::<u16>())
+                .prop_map(|(ip, port)| SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn address_attributes_round_trip(addr in arb_socket_addr(), tid in any::<[u8; 12]>()) {
+            let tid = TransactionId(tid);
+            let attrs = vec![
+                (Attribute::MappedAddress, Attribute::generate_mapped_address_value(&addr)),
+                (Attribute::XORMappedAddress, Attribute::generate_xor_mapped_address_value(&addr, &tid)),
+                (Attribute::OtherAddress, Attribute::generate_other_address_value(&addr)),
+                (Attribute::ResponseOrigin, Attribute::generate_response_origin_value(&addr)),
+            ];
+            let mut msg = Message::new(Method::Binding, Class::SuccessResponse, Some(attrs));
+            msg.header.transaction_id = tid;
+            let msg = Message::from_raw(&msg.to_raw()).unwrap();
+
+            prop_assert_eq!(Attribute::get_mapped_address(&msg), Some(addr));
+            prop_assert_eq!(Attribute::get_xor_mapped_address(&msg), Some(addr));
+            prop_assert_eq!(Attribute::get_other_address(&msg), Some(addr));
+            prop_assert_eq!(Attribute::get_response_origin(&msg), Some(addr));
+        }
+
+        #[test]
+        fn xor_mapped_address_is_obfuscated(addr in arb_socket_addr(), tid in any::<[u8; 12]>()) {
+            let tid = TransactionId(tid);
+            let value = Attribute::generate_xor_mapped_address_value(&addr, &tid);
+            prop_assert_eq!(&value[..2], &Attribute::generate_mapped_address_value(&addr)[..2]);
+            prop_assert_eq!(
+                u16::from_be_bytes([value[2], value[3]]),
+                addr.port() ^ (MAGIC_COOKIE >> 16) as u16
+            );
+            prop_assert_eq!(
+                AttributeValue::decode(Attribute::XORMappedAddress, &value, &tid).unwrap(),
+                AttributeValue::XORMappedAddress(addr)
+            );
+        }
+    }
+
+    #[test]
+    fn error_code_encode_and_decode() {
+        let codes = [
+            300, 400, 401, 403, 405, 420, 437, 438, 440, 441, 442, 443, 446, 447, 486, 487, 500,
+            508, 599,
+        ];
+        for code in codes.iter() {
+            let error_code = ErrorCode::from(*code, String::from("reason"));
+            assert_eq!(error_code.code(), *code);
+            assert_eq!(error_code.reason(), "reason");
+            assert_eq!(*code == 599, matches!(error_code, ErrorCode::Unknown(..)));
+
+            let value = Attribute::generate_error_code_value(&error_code).unwrap();
+            let msg = Message::new(
+                Method::Binding,
+                Class::ErrorResponse,
+                Some(vec![(Attribute::ErrorCode, value)]),
+            );
+            let raw = msg.to_raw();
+            assert_eq!(raw.len(), HEADER_BYTE_SIZE + 4 + 12);
+            let msg = Message::from_raw(&raw).unwrap();
+            assert_eq!(Attribute::get_error_code(&msg), Some(error_code));
+        }
+
+        for code in [0, 42, 299, 700, 999, 1234].iter() {
+            let error_code = ErrorCode::from(*code, String::new());
+            assert!(Attribute::generate_error_code_value(&error_code).is_err());
+            let mut msg = Message::new(Method::Binding, Class::ErrorResponse, None);
+            assert!(matches!(
+                msg.add(AttributeValue::ErrorCode {
+                    code: *code,
+                    reason: String::new(),
+                }),
+                Err(STUNClientError::InvalidAttributeValueError(
+                    Attribute::ErrorCode,
+                    _
+                ))
+            ));
+        }
+
+        let reason = "\u{3042}".repeat(MAX_REASON_PHRASE_CHARS - 1);
+        let error_code = ErrorCode::from(400, reason.clone());
+        assert!(Attribute::generate_error_code_value(&error_code).is_ok());
+        let error_code = ErrorCode::from(400, reason + "a");
+        assert!(matches!(
+            Attribute::generate_error_code_value(&error_code),
+            Err(STUNClientError::InvalidAttributeValueError(
+                Attribute::ErrorCode,
+                _
+            ))
+        ));
+        let tid = TransactionId::random();
+        assert!(matches!(
+            AttributeValue::decode(Attribute::ErrorCode, &[0, 0, 2, 0], &tid),
+            Err(STUNClientError::InvalidAttributeValueError(
+                Attribute::ErrorCode,
+                _
+            ))
+        ));
+        let mut value = vec![0, 0, 4, 0];
+        value.extend("a".repeat(MAX_REASON_PHRASE_BYTE_SIZE + 1).as_bytes());
+        assert!(matches!(
+            AttributeValue::decode(Attribute::ErrorCode, &value, &tid),
+            Err(STUNClientError::InvalidAttributeLengthError(
+                Attribute::ErrorCode,
+                _
+            ))
+        ));
+        assert!(matches!(
+            AttributeValue::decode(Attribute::ErrorCode, &[0, 0, 4, 100], &tid),
+            Err(STUNClientError::InvalidAttributeValueError(
+                Attribute::ErrorCode,
+                _
+            ))
+        ));
+    }
+
+    #[test]
+    fn unknown_comprehension_required_attributes_are_reported() {
+        let attrs = vec![
+            (Attribute::Unknown(0x7F01), vec![1, 2, 3, 4]),
+            (Attribute::Unknown(0x8F01), vec![1, 2, 3, 4]),
+            (Attribute::Software, b"test".to_vec()),
+            (Attribute::Unknown(0x0030), vec![]),
+            (Attribute::Unknown(0x7F01), vec![]),
+        ];
+        let req = Message::new(Method::Binding, Class::Request, Some(attrs));
+        let req = Message::from_raw(&req.to_raw()).unwrap();
+        let unknown = req.get_unknown_comprehension_required_attrs();
+        assert_eq!(
+            unknown,
+            vec![Attribute::Unknown(0x7F01), Attribute::Unknown(0x0030)]
+        );
+
+        let mut res = Message::new(Method::Binding, Class::ErrorResponse, None);
+        res.header.transaction_id = req.get_transaction_id();
+        res.add(AttributeValue::ErrorCode {
+            code: 420,
+            reason: String::from("Unknown Attribute"),
+        })
+        .unwrap();
+        res.add(AttributeValue::UnknownAttributes(unknown.clone()))
+            .unwrap();
+        let raw = res.to_raw();
+        assert_eq!(
+            &raw[raw.len() - 8..],
+            &[0x00, 0x0A, 0x00, 0x04, 0x7F, 0x01, 0x00, 0x30]
+        );
+
+        let res = Message::from_raw(&raw).unwrap();
+        assert!(matches!(
+            Attribute::get_error_code(&res),
+            Some(ErrorCode::UnknownAttribute(_))
+        ));
+        assert_eq!(Attribute::get_unknown_attributes(&res), Some(unknown));
+        assert!(res.get_unknown_comprehension_required_attrs().is_empty());
+    }
+
+    // RFC5769 Section 2.4: Sample Request with Long-Term Authentication
+    const SAMPLE_LONG_TERM_REQUEST: [u8; 116] = [
+        0x00, 0x01, 0x00, 0x60, 0x21, 0x12, 0xa4, 0x42, 0x78, 0xad, 0x34, 0x33, 0xc6, 0xad, 0x72,
+        0xc0, 0x29, 0xda, 0x41, 0x2e, 0x00, 0x06, 0x00, 0x12, 0xe3, 0x83, 0x9e, 0xe3, 0x83, 0x88,
+        0xe3, 0x83, 0xaa, 0xe3, 0x83, 0x83, 0xe3, 0x82, 0xaf, 0xe3, 0x82, 0xb9, 0x00, 0x00, 0x00,
+        0x15, 0x00, 0x1c, 0x66, 0x2f, 0x2f, 0x34, 0x39, 0x39, 0x6b, 0x39, 0x35, 0x34, 0x64, 0x36,
+        0x4f, 0x4c, 0x33, 0x34, 0x6f, 0x4c, 0x39, 0x46, 0x53, 0x54, 0x76, 0x79, 0x36, 0x34, 0x73,
+        0x41, 0x00, 0x14, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e, 0x6f, 0x72,
+        0x67, 0x00, 0x00, 0x08, 0x00, 0x14, 0xf6, 0x70, 0x24, 0x65, 0x6d, 0xd6, 0x4a, 0x3e, 0x02,
+        0xb8, 0xe0, 0x71, 0x2e, 0x85, 0xc9, 0xa2, 0x8c, 0xa8, 0x96, 0x66,
+    ];
+
+    #[test]
+    fn rfc5769_long_term_request_uses_prepared_password() {
+        let msg = Message::from_raw(&SAMPLE_LONG_TERM_REQUEST).unwrap();
+        let username = Attribute::get_username(&msg).unwrap();
+        let realm = Attribute::get_realm(&msg).unwrap();
+        assert_eq!(username, "\u{30DE}\u{30C8}\u{30EA}\u{30C3}\u{30AF}\u{30B9}");
+        assert_eq!(realm, "example.org");
+
+        let key = PasswordAlgorithm::MD5
+            .derive_key(&username, &realm, "The\u{00AD}M\u{00AA}tr\u{2168}")
+            .unwrap();
+        msg.verify_integrity(&key).unwrap();
+    }
+
+    #[test]
+    fn string_attributes_are_prepared_and_limited() {
+        let tid = TransactionId([0; TRANSACTION_ID_BYTE_SIZE]);
+        // SASLprep maps non-ASCII spaces to SPACE and removes soft hyphens.
+        assert_eq!(
+            AttributeValue::Username(String::from("us\u{00AD}er\u{2003}name"))
+                .encode(&tid)
+                .unwrap(),
+            b"user name".to_vec()
+        );
+        assert!(matches!(
+            AttributeValue::Realm(String::from("realm\u{0007}")).encode(&tid),
+            Err(STUNClientError::InvalidAttributeValueError(
+                Attribute::Realm,
+                _
+            ))
+        ));
+
+        // The largest sizes accepted when encoding and decoding.
+        let cases = [
+            (Attribute::Username, 508, 512),
+            (Attribute::Realm, 508, 763),
+            (Attribute::Nonce, 508, 763),
+            (Attribute::AlternateDomain, 255, 255),
+        ];
+        let value = |attr: Attribute, len: usize| {
+            let s = "a".repeat(len);
+            match attr {
+                Attribute::Username => AttributeValue::Username(s),
+                Attribute::Realm => AttributeValue::Realm(s),
+                Attribute::Nonce => AttributeValue::Nonce(s),
+                _ => AttributeValue::AlternateDomain(s),
+            }
+        };
+        for (attr, max_encoded, max_decoded) in cases.iter() {
+            assert!(value(*attr, *max_encoded).encode(&tid).is_ok());
+            assert!(matches!(
+                value(*attr, max_encoded + 1).encode(&tid),
+                Err(STUNClientError::InvalidAttributeLengthError(_, _))
+            ));
+
+            let raw = "a".repeat(*max_decoded);
+            assert!(AttributeValue::decode(*attr, raw.as_bytes(), &tid).is_ok());
+            let raw = "a".repeat(max_decoded + 1);
+            assert!(matches!(
+                AttributeValue::decode(*attr, raw.as_bytes(), &tid),
+                Err(STUNClientError::InvalidAttributeLengthError(_, _))
+            ));
+            // Values received from a server are echoed as-is.
+            assert!(AttributeValue::Opaque(*attr, raw.into_bytes())
+                .encode(&tid)
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn userhash_and_alternate_server_attributes() {
+        let userhash = Attribute::generate_userhash_value("user", "realm").unwrap();
+        assert_eq!(userhash, Sha256::digest(b"user:realm").to_vec());
+        let alternate_server: SocketAddr = "[2001:db8::1]:3478".parse().unwrap();
+
+        let mut msg = Message::new(Method::Binding, Class::ErrorResponse, None);
+        msg.add(AttributeValue::UserHash(userhash.clone())).unwrap();
+        msg.add(AttributeValue::AlternateServer(alternate_server))
+            .unwrap();
+        msg.add(AttributeValue::AlternateDomain(String::from(
+            "stun.example.org",
+        )))
+        .unwrap();
+        let msg = Message::from_raw(&msg.to_raw()).unwrap();
+        assert_eq!(Attribute::get_userhash(&msg), Some(userhash));
+        assert_eq!(
+            Attribute::get_alternate_server(&msg),
+            Some(alternate_server)
+        );
+        assert_eq!(
+            Attribute::get_alternate_domain(&msg),
+            Some(String::from("stun.example.org"))
+        );
+        assert!(msg.get_unknown_comprehension_required_attrs().is_empty());
+
+        assert!(
+            AttributeValue::decode(Attribute::UserHash, &[0; 31], &msg.get_transaction_id())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn transaction_ids_are_random() {
+        let ids: Vec<TransactionId> = (0..16).map(|_| TransactionId::random()).collect();
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[i + 1..].contains(id));
+        }
+
+        let msg = Message::new(Method::Binding, Class::Request, None);
+        let raw = msg.to_raw();
+        assert_eq!(msg.get_transaction_id().as_bytes(), &raw[8..20]);
+        assert_eq!(
+            MessageRef::from_raw(&raw).unwrap().get_transaction_id(),
+            msg.get_transaction_id()
+        );
+    }
+
+    #[test]
+    fn message_builder_uses_given_transaction_id() {
+        let tid = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+        let builder = MessageBuilder::new(Method::Binding, Class::SuccessResponse)
+            .transaction_id(TransactionId(tid))
+            .attr(AttributeValue::XORMappedAddress(
+                "192.0.2.1:32853".parse().unwrap(),
+            ))
+            .attr(AttributeValue::Software(String::from("stun-client")))
+            .message_integrity(b"key")
+            .fingerprint();
+        let msg = builder.build().unwrap();
+        assert_eq!(msg.get_transaction_id(), TransactionId(tid));
+        assert_eq!(builder.build().unwrap().to_raw(), msg.to_raw());
+        let attrs: Vec<Attribute> = msg.get_raw_attrs().iter().map(|(k, _)| *k).collect();
+        assert_eq!(
+            attrs,
+            vec![
+                Attribute::XORMappedAddress,
+                Attribute::Software,
+                Attribute::MessageIntegrity,
+                Attribute::Fingerprint
+            ]
+        );
+
+        let raw = msg.to_raw();
+        let mut buf = [0xff; 128];
+        assert_eq!(builder.encode_into(&mut buf).unwrap(), raw.len());
+        assert_eq!(&buf[..raw.len()], &raw[..]);
+        let re_built_msg = Message::from_raw(&buf[..raw.len()]).unwrap();
+        assert!(re_built_msg.verify_integrity(b"key").is_ok());
+        assert!(matches!(
+            builder.encode_into(&mut buf[..raw.len() - 1]),
+            Err(STUNClientError::BufferTooSmallError(len, _)) if len == raw.len()
+        ));
+
+        let mut bytes = BytesMut::from(&b"head"[..]);
+        assert_eq!(builder.encode_into_bytes(&mut bytes).unwrap(), raw.len());
+        assert_eq!(&bytes[..4], b"head");
+        assert_eq!(&bytes[4..], &raw[..]);
+    }
+
+    #[test]
+    fn message_type_interleaves_method_and_class() {
+        let cases = [
+            (Method::Binding, Class::Request, 0x0001),
+            (Method::Binding, Class::Indication, 0x0011),
+            (Method::Binding, Class::SuccessResponse, 0x0101),
+            (Method::Binding, Class::ErrorResponse, 0x0111),
+            (Method::Allocate, Class::Request, 0x0003),
+            (Method::Allocate, Class::SuccessResponse, 0x0103),
+            (Method::Allocate, Class::ErrorResponse, 0x0113),
+            (Method::Refresh, Class::Request, 0x0004),
+            (Method::Refresh, Class::SuccessResponse, 0x0104),
+            (Method::Send, Class::Indication, 0x0016),
+            (Method::Data, Class::Indication, 0x0017),
+            (Method::CreatePermission, Class::Request, 0x0008),
+            (Method::CreatePermission, Class::ErrorResponse, 0x0118),
+            (Method::ChannelBind, Class::Request, 0x0009),
+            (Method::ChannelBind, Class::SuccessResponse, 0x0109),
+            (Method::Connect, Class::Request, 0x000A),
+            (Method::ConnectionBind, Class::Request, 0x000B),
+            (Method::ConnectionAttempt, Class::Indication, 0x001C),
+            (Method::GoogPing, Class::Request, 0x0200),
+            (Method::GoogPing, Class::SuccessResponse, 0x0300),
+            (Method::Unknown(0x0FFF), Class::Request, 0x3EEF),
+            (Method::Unknown(0x0FFF), Class::ErrorResponse, 0x3FFF),
+        ];
+        for (method, class, message_type) in cases.iter() {
+            let header = Header::new(*method, *class, 0, TransactionId([0; 12]));
+            assert_eq!(header.message_type(), *message_type);
+            assert_eq!(Header::decode_method(*message_type), *method);
+            assert_eq!(Header::decode_class(*message_type), *class);
+        }
+
+        for method in 0..=0x0FFF {
+            for class in [
+                Class::Request,
+                Class::Indication,
+                Class::SuccessResponse,
+                Class::ErrorResponse,
+            ]
+            .iter()
+            {
+                let msg = Message::new(Method::from_u16(method), *class, None);
+                let re_built_msg = Message::from_raw(&msg.to_raw()).unwrap();
+                assert_eq!(re_built_msg.get_method().to_u16(), method);
+                assert_eq!(re_built_msg.get_class(), *class);
+            }
+        }
+    }
+
+    #[test]
+    fn header_is_validated() {
+        let raw = Message::new(Method::Binding, Class::Request, None).to_raw();
+        assert!(Header::from_raw(&raw).is_ok());
+
+        let mut bad_type = raw.clone();
+        bad_type[0] |= 0x80;
+        assert!(matches!(
+            Message::from_raw(&bad_type),
+            Err(STUNClientError::InvalidMessageTypeError(0x8001))
+        ));
+
+        let mut bad_cookie = raw.clone();
+        bad_cookie[4] = 0;
+        assert!(matches!(
+            Message::from_raw(&bad_cookie),
+            Err(STUNClientError::MagicCookieMismatchError(0x0012A442))
+        ));
+        assert!(Message::from_raw_lenient(&bad_cookie).is_ok());
+
+        let mut unaligned = raw.clone();
+        unaligned[3] = 2;
+        unaligned.extend(&[0, 0]);
+        assert!(matches!(
+            Message::from_raw(&unaligned),
+            Err(STUNClientError::UnalignedLengthError(2))
+        ));
+
+        let mut trailing = raw.clone();
+        trailing.extend(&[0, 0, 0, 0]);
+        assert!(matches!(
+            Message::from_raw(&trailing),
+            Err(STUNClientError::LengthMismatchError(0, 4))
+        ));
+        assert!(matches!(
+            Message::from_raw_lenient(&trailing),
+            Err(STUNClientError::LengthMismatchError(0, 4))
+        ));
+
+        assert!(matches!(
+            Message::from_raw(&raw[..HEADER_BYTE_SIZE - 1]),
+            Err(STUNClientError::ParseError())
+        ));
+    }
+
+    #[test]
+    fn malformed_attribute_values_are_reported() {
+        let tid = TransactionId([0; TRANSACTION_ID_BYTE_SIZE]);
+        assert!(matches!(
+            AttributeValue::decode(Attribute::XORMappedAddress, &[0, 1, 0], &tid),
+            Err(STUNClientError::InvalidAttributeLengthError(
+                Attribute::XORMappedAddress,
+                3
+            ))
+        ));
+        assert!(matches!(
+            AttributeValue::decode(Attribute::MappedAddress, &[0, 2, 0, 0, 1, 2, 3, 4], &tid),
+            Err(STUNClientError::InvalidAttributeLengthError(
+                Attribute::MappedAddress,
+                8
+            ))
+        ));
+        assert!(matches!(
+            AttributeValue::decode(Attribute::OtherAddress, &[0, 3, 0, 0, 1, 2, 3, 4], &tid),
+            Err(STUNClientError::InvalidAttributeValueError(
+                Attribute::OtherAddress,
+                _
+            ))
+        ));
+        assert!(matches!(
+            AttributeValue::decode(Attribute::ErrorCode, &[0, 0], &tid),
+            Err(STUNClientError::InvalidAttributeLengthError(
+                Attribute::ErrorCode,
+                2
+            ))
+        ));
+        assert!(matches!(
+            AttributeValue::decode(Attribute::Software, &[0xff], &tid),
+            Err(STUNClientError::InvalidAttributeValueError(
+                Attribute::Software,
+                _
+            ))
+        ));
+
+        let mut raw = Message::new(Method::Binding, Class::SuccessResponse, None).to_raw();
+        raw.extend(&[0x00, 0x20, 0x00, 0x02, 0x00, 0x01, 0x00, 0x00]);
+        raw[3] = 8;
+        let msg = Message::from_raw(&raw).unwrap();
+        assert_eq!(Attribute::get_xor_mapped_address(&msg), None);
+    }
+
+    #[test]
+    fn attribute_order_and_duplicates_are_preserved() {
+        let attrs = vec![
+            (Attribute::Software, b"first".to_vec()),
+            (Attribute::Username, b"user".to_vec()),
+            (Attribute::Software, b"second".to_vec()),
+        ];
+        let msg = Message::new(Method::Binding, Class::Request, Some(attrs.clone()));
+        let re_built_msg = Message::from_raw(&msg.to_raw()).unwrap();
+        assert_eq!(re_built_msg.get_raw_attrs(), &attrs[..]);
+        assert_eq!(
+            re_built_msg.get_raw_attr_values(Attribute::Software),
+            vec![b"first".to_vec(), b"second".to_vec()]
+        );
+    }
+
+    #[test]
+    fn adding_attributes_removes_stale_trailers() {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        msg.add_message_integrity(b"password");
+        msg.add_fingerprint();
+        msg.add(AttributeValue::Software(String::from("stun-client")))
+            .unwrap();
+        assert_eq!(
+            msg.get_raw_attrs(),
+            &[(Attribute::Software, b"stun-client".to_vec())]
+        );
+        let raw = msg.to_raw();
+        assert_eq!(Message::from_raw(&raw).unwrap(), msg);
+
+        let attrs = vec![
+            (
+                Attribute::MessageIntegrity,
+                vec![0; MESSAGE_INTEGRITY_BYTE_SIZE],
+            ),
+            (Attribute::Software, b"stun-client".to_vec()),
+            (Attribute::Fingerprint, vec![0; FINGERPRINT_BYTE_SIZE]),
+        ];
+        let msg = Message::new(Method::Binding, Class::Request, Some(attrs));
+        assert_eq!(
+            msg.get_raw_attrs(),
+            &[(Attribute::Software, b"stun-client".to_vec())]
+        );
+
+        // Trailers that are already last are kept.
+        let attrs = vec![
+            (Attribute::Software, b"stun-client".to_vec()),
+            (
+                Attribute::MessageIntegrity,
+                vec![0; MESSAGE_INTEGRITY_BYTE_SIZE],
+            ),
+        ];
+        let msg = Message::new(Method::Binding, Class::Request, Some(attrs.clone()));
+        assert_eq!(msg.get_raw_attrs(), &attrs[..]);
+    }
+
+    #[test]
+    fn attributes_after_message_integrity_are_ignored() {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        msg.add_message_integrity(b"password");
+        let mut raw = msg.to_raw();
+        raw.extend(&[0x80, 0x22, 0x00, 0x04, b't', b'e', b's', b't']);
+        raw[3] += 8;
+        let re_built_msg = Message::from_raw(&raw).unwrap();
+        assert_eq!(re_built_msg.get_raw_attr_value(Attribute::Software), None);
+        assert!(re_built_msg.verify_integrity(b"password").is_ok());
+
+        msg.add_fingerprint();
+        let mut raw = msg.to_raw();
+        raw.extend(&[0x80, 0x22, 0x00, 0x04, b't', b'e', b's', b't']);
+        raw[3] += 8;
+        assert!(Message::from_raw(&raw).is_err());
+    }
+
+    #[test]
+    fn message_integrity_is_verified_with_the_same_key() {
+        let attrs = vec![(Attribute::Software, b"stun-client".to_vec())];
+        let mut msg = Message::new(Method::Binding, Class::Request, Some(attrs));
+        msg.add_message_integrity(b"password");
+
+        let re_built_msg = Message::from_raw(&msg.to_raw()).unwrap();
+        assert!(re_built_msg.verify_integrity(b"password").is_ok());
+        assert!(matches!(
+            re_built_msg.verify_integrity(b"wrong"),
+            Err(STUNClientError::IntegrityMismatchError())
+        ));
+
+        let unsigned = Message::new(Method::Binding, Class::SuccessResponse, None);
+        assert!(matches!(
+            unsigned.verify_integrity(b"password"),
+            Err(STUNClientError::MissingIntegrityError())
+        ));
+    }
+
+    #[test]
+    fn modified_messages_are_verified_against_their_new_bytes() {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        msg.add_message_integrity(b"password");
+        let mut msg = Message::from_raw(&msg.to_raw()).unwrap();
+        assert!(msg.verify_integrity(b"password").is_ok());
+
+        msg.add_message_integrity(b"other");
+        assert!(msg.verify_integrity(b"other").is_ok());
+        assert!(msg.verify_integrity(b"password").is_err());
+
+        msg.add_message_integrity_sha256(b"sha256");
+        assert!(msg.verify_integrity(b"sha256").is_ok());
+
+        let mut msg = Message::from_raw(&msg.to_raw()).unwrap();
+        msg.add_fingerprint();
+        let raw = msg.to_raw();
+        assert!(Message::from_raw(&raw).is_ok());
+        assert!(msg.verify_integrity(b"sha256").is_ok());
+        assert_eq!(msg.raw, None);
+    }
+
+    #[test]
+    fn truncated_message_integrity_sha256_is_verified() {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        msg.add_message_integrity(b"password");
+        msg.add_truncated_message_integrity_sha256(b"password", 16)
+            .unwrap();
+        assert!(msg
+            .add_truncated_message_integrity_sha256(b"password", 18)
+            .is_err());
+
+        let re_built_msg = Message::from_raw(&msg.to_raw()).unwrap();
+        assert_eq!(
+            re_built_msg
+                .get_raw_attr_value(Attribute::MessageIntegritySHA256)
+                .unwrap()
+                .len(),
+            16
+        );
+        assert!(re_built_msg.verify_integrity(b"password").is_ok());
+        assert!(re_built_msg.verify_integrity(b"wrong").is_err());
+    }
+
+    #[test]
+    fn fingerprint_is_appended_last_and_validated() {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        msg.add_message_integrity(b"password");
+        msg.add_fingerprint();
+        let mut raw = msg.to_raw();
+        assert_eq!(
+            &raw[raw.len() - 8..raw.len() - 4],
+            &[0x80, 0x28, 0x00, 0x04]
+        );
+        assert!(Message::from_raw(&raw)
+            .unwrap()
+            .verify_integrity(b"password")
+            .is_ok());
+
+        let last = raw.len() - 1;
+        raw[last] ^= 0xff;
+        assert!(matches!(
+            Message::from_raw(&raw),
+            Err(STUNClientError::FingerprintMismatchError())
+        ));
+    }
+
+    #[test]
+    fn stun_messages_are_demultiplexed() {
+        let mut msg = Message::new(Method::Binding, Class::Request, None);
+        msg.add_fingerprint();
+        let mut raw = msg.to_raw();
+        assert!(Message::is_stun(&raw));
+
+        // RFC7983: DTLS records start with 20 to 63 and RTP packets with 128 to 191.
+        for first in [22u8, 0x80] {
+            let mut other = raw.clone();
+            other[0] = first;
+            assert!(!Message::is_stun(&other));
+        }
+        assert!(!Message::is_stun(&raw[..raw.len() - 4]));
+        assert!(!Message::is_stun(&[]));
+
+        // A DTLS record that looks like a header with the magic cookie and no FINGERPRINT.
+        let mut header = Message::new(Method::Binding, Class::Request, None).to_raw();
+        assert!(Message::is_stun(&header));
+        header[0] = 0x14;
+        assert!(Header::from_raw(&header).is_ok());
+        assert!(!Message::is_stun(&header));
+        assert!(!Message::is_stun_lenient(&header));
+
+        let last = raw.len() - 1;
+        raw[last] ^= 0xff;
+        assert!(!Message::is_stun(&raw));
+
+        let mut rfc3489 = Message::new(Method::Binding, Class::Request, None).to_raw();
+        rfc3489[4..8].copy_from_slice(&[1, 2, 3, 4]);
+        assert!(!Message::is_stun(&rfc3489));
+        assert!(Message::is_stun_lenient(&rfc3489));
+    }
+
+    #[test]
+    fn security_features_are_decoded_from_nonce_cookie() {
+        let features = SecurityFeatures::from_nonce("obMatJos2gAAAnonce").unwrap();
+        assert!(features.password_algorithms);
+        assert!(!features.username_anonymity);
+        assert_eq!(
+            SecurityFeatures::from_nonce("f//499k954d6OL34oL9FSTvy64sA"),
+            None
+        );
+    }
+
+    #[test]
+    fn password_algorithm_negotiation_prefers_sha256() {
+        let attrs = vec![(
+            Attribute::PasswordAlgorithms,
+            Attribute::generate_password_algorithms_value(&[
+                PasswordAlgorithm::MD5,
+                PasswordAlgorithm::SHA256,
+            ]),
+        )];
+        let msg = Message::new(Method::Binding, Class::ErrorResponse, Some(attrs));
+        assert_eq!(
+            PasswordAlgorithm::negotiate(&msg).unwrap(),
+            (PasswordAlgorithm::SHA256, Attribute::MessageIntegritySHA256)
+        );
+
+        let msg = Message::new(Method::Binding, Class::ErrorResponse, None);
+        assert_eq!(
+            PasswordAlgorithm::negotiate(&msg).unwrap(),
+            (PasswordAlgorithm::MD5, Attribute::MessageIntegrity)
+        );
+    }
 }
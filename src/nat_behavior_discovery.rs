@@ -1,6 +1,5 @@
 //! This module is for NAT Behavior Discovery based on RFC5780.
 //! To use this module, the STUN server side must support the OTHER-ADDRESS and CHANGE-REQUEST attributes.
-use std::collections::HashMap;
 use std::net::IpAddr;
 
 use async_std::net::{SocketAddr, ToSocketAddrs};
@@ -67,7 +66,7 @@ pub async fn check_nat_mapping_behavior<A: ToSocketAddrs>(
     // Test1
     // Send a Binding request and check the Endpoint mapped to NAT.
     // Compare with the IP of the NIC and check if it is behind the NAT.
-    let t1_res = client.binding_request(&stun_addr, None).await?;
+    let t1_res = client.binding_request(&stun_addr, None).await?.message;
     let other_addr = Attribute::get_other_address(&t1_res).ok_or(
         STUNClientError::NotSupportedError(String::from("OTHER-ADDRESS")),
     )?;
@@ -101,7 +100,7 @@ pub async fn check_nat_mapping_behavior<A: ToSocketAddrs>(
     // Test2
     // Send Binding Request to IP:Port of OTHER-ADDRESS.
     // Compare Test1 and Test2 XOR-MAPPED-ADDRESS to check if it is EIM-NAT.
-    let t2_res = client.binding_request(&other_addr, None).await?;
+    let t2_res = client.binding_request(&other_addr, None).await?.message;
     result.test2_xor_mapped_addr = Some(Attribute::get_xor_mapped_address(&t2_res).ok_or(
         STUNClientError::NotSupportedError(String::from("XOR-MAPPED-ADDRESS")),
     )?);
@@ -117,7 +116,7 @@ pub async fn check_nat_mapping_behavior<A: ToSocketAddrs>(
     // stun_addr is a known value, so it's okay to unwrap it.
     let mut t3_addr = stun_addr.to_socket_addrs().await.unwrap().next().unwrap();
     t3_addr.set_port(other_addr.port());
-    let t3_res = client.binding_request(&t3_addr, None).await?;
+    let t3_res = client.binding_request(&t3_addr, None).await?.message;
     result.test3_xor_mapped_addr = Some(Attribute::get_xor_mapped_address(&t3_res).ok_or(
         STUNClientError::NotSupportedError(String::from("XOR-MAPPED-ADDRESS")),
     )?);
@@ -131,13 +130,14 @@ pub async fn check_nat_mapping_behavior<A: ToSocketAddrs>(
 }
 
 /// Check NAT filtering behavior.
+/// A filtering NAT is detected by requests that time out, so each of them waits for `recv_timeout_ms` of the Client.
 pub async fn check_nat_filtering_behavior<A: ToSocketAddrs>(
     client: &mut Client,
     stun_addr: A,
 ) -> Result<NATFilteringTypeResult, STUNClientError> {
     // Test1
     // Send a Binding request and check the Endpoint mapped to NAT.
-    let t1_res = client.binding_request(&stun_addr, None).await?;
+    let t1_res = client.binding_request(&stun_addr, None).await?.message;
     let xor_mapped_addr = Some(Attribute::get_xor_mapped_address(&t1_res).ok_or(
         STUNClientError::NotSupportedError(String::from("XOR-MAPPED-ADDRESS")),
     )?);
@@ -146,14 +146,15 @@ pub async fn check_nat_filtering_behavior<A: ToSocketAddrs>(
     // Send Binding Request with the "change IP" and "change port" flags of CHANGE-REQUEST turned on.
     // As a result, the response is sent from IP:Port which is different from the sent IP:Port.
     // If the response can be received, it is EIF-NAT.
-    let mut attrs = HashMap::new();
-    let change_request = Attribute::generate_change_request_value(true, true);
-    attrs.insert(Attribute::ChangeRequest, change_request);
+    let attrs = vec![AttributeValue::ChangeRequest {
+        change_ip: true,
+        change_port: true,
+    }];
     let t2_res = client.binding_request(&stun_addr, Some(attrs)).await;
     match t2_res {
         Ok(_) => {
             return Ok(NATFilteringTypeResult {
-                xor_mapped_addr: xor_mapped_addr,
+                xor_mapped_addr,
                 filtering_type: NATFilteringType::EndpointIndependent,
             })
         }
@@ -169,25 +170,22 @@ pub async fn check_nat_filtering_behavior<A: ToSocketAddrs>(
     // Send a binding request with only the "change port" flag in CHANGE-REQUEST turned on.
     // As a result, the response is sent from Port which is different from the sent Port.(Same IP address)
     // If the response can be received, it is ADF-NAT, and if it cannot be received, it is APDF-NAT.
-    let mut attrs = HashMap::new();
-    let change_request = Attribute::generate_change_request_value(false, true);
-    attrs.insert(Attribute::ChangeRequest, change_request);
+    let attrs = vec![AttributeValue::ChangeRequest {
+        change_ip: false,
+        change_port: true,
+    }];
     let t3_res = client.binding_request(&stun_addr, Some(attrs)).await;
     match t3_res {
-        Ok(_) => {
-            return Ok(NATFilteringTypeResult {
-                xor_mapped_addr: xor_mapped_addr,
-                filtering_type: NATFilteringType::AddressDependent,
-            })
-        }
+        Ok(_) => Ok(NATFilteringTypeResult {
+            xor_mapped_addr,
+            filtering_type: NATFilteringType::AddressDependent,
+        }),
         Err(e) => match e {
-            STUNClientError::TimeoutError() => {
-                return Ok(NATFilteringTypeResult {
-                    xor_mapped_addr: xor_mapped_addr,
-                    filtering_type: NATFilteringType::AddressAndPortDependent,
-                })
-            }
-            _ => return Err(e),
+            STUNClientError::TimeoutError() => Ok(NATFilteringTypeResult {
+                xor_mapped_addr,
+                filtering_type: NATFilteringType::AddressAndPortDependent,
+            }),
+            _ => Err(e),
         },
     }
 }
@@ -0,0 +1,298 @@
+//! This module answers STUN requests received on the Client socket, e.g. ICE connectivity checks from peers.
+use std::fmt;
+
+use async_std::net::SocketAddr;
+
+use super::error::*;
+use super::message::*;
+
+/// Handles STUN requests received on the Client socket.
+pub trait RequestHandler: Send {
+    /// Returns the response to send back to the source of the request, or None to send nothing.
+    fn handle(&mut self, request: &Message, source: SocketAddr) -> Option<Message>;
+}
+
+/// A STUN request received on the Client socket.
+#[derive(Clone, Debug)]
+pub struct RequestEvent {
+    /// The request message.
+    pub request: Message,
+    /// The address the request came from.
+    pub source: SocketAddr,
+    /// The response sent back by the RequestHandler, if any.
+    pub response: Option<Message>,
+}
+
+/// The default RequestHandler.
+/// It answers Binding requests with the XOR-MAPPED-ADDRESS of the sender as a STUN server does,
+/// and ignores requests of other methods.
+/// FINGERPRINT is added to the response if the request has it.
+/// The ICE attributes PRIORITY, USE-CANDIDATE, ICE-CONTROLLED and ICE-CONTROLLING of connectivity checks
+/// are understood, and other unknown comprehension-required attributes are rejected with 420 Unknown Attribute.
+#[derive(Clone, Default)]
+pub struct BindingRequestHandler {
+    username: Option<String>,
+    key: Option<Vec<u8>>,
+}
+
+impl BindingRequestHandler {
+    /// Create a BindingRequestHandler answering every Binding request.
+    pub fn new() -> BindingRequestHandler {
+        BindingRequestHandler::default()
+    }
+
+    /// Create a BindingRequestHandler that authenticates requests with short-term credentials
+    /// as described in RFC8489 Section 9.1, e.g. the local ICE username and password.
+    /// Requests without USERNAME and MESSAGE-INTEGRITY are rejected with 400 Bad Request,
+    /// and those with another username or an invalid integrity with 401 Unauthorized.
+    /// Responses to authenticated requests are signed with the same key.
+    pub fn with_short_term_credentials(
+        username: &str,
+        password: &str,
+    ) -> Result<BindingRequestHandler, STUNClientError> {
+        // RFC8489: key = OpaqueString(password)
+        let key = stringprep::saslprep(password).map_err(|e| {
+            STUNClientError::AuthenticationError(format!("invalid password: {}", e))
+        })?;
+        Ok(BindingRequestHandler {
+            username: Some(String::from(username)),
+            key: Some(key.as_bytes().to_vec()),
+        })
+    }
+
+    fn authenticate(&self, request: &Message) -> Result<Option<Integrity>, ErrorCode> {
+        let (username, key) = match (&self.username, &self.key) {
+            (Some(username), Some(key)) => (username, key),
+            _ => return Ok(None),
+        };
+
+        let integrity = match request.get_raw_attr_value(Attribute::MessageIntegritySHA256) {
+            Some(mac) => Integrity::SHA256(key.clone(), mac.len()),
+            None if request
+                .get_raw_attr_value(Attribute::MessageIntegrity)
+                .is_some() =>
+            {
+                Integrity::SHA1(key.clone())
+            }
+            None => return Err(ErrorCode::BadRequest(String::from("Bad Request"))),
+        };
+        match Attribute::get_username(request) {
+            Some(u) if &u == username => {}
+            Some(_) => return Err(ErrorCode::Unauthorized(String::from("Unauthorized"))),
+            None => return Err(ErrorCode::BadRequest(String::from("Bad Request"))),
+        }
+        if request.verify_integrity(key).is_err() {
+            return Err(ErrorCode::Unauthorized(String::from("Unauthorized")));
+        }
+        Ok(Some(integrity))
+    }
+
+    fn response(
+        request: &Message,
+        class: Class,
+        attrs: Vec<AttributeValue>,
+        integrity: Option<&Integrity>,
+    ) -> Option<Message> {
+        let mut builder = MessageBuilder::new(Method::Binding, class)
+            .transaction_id(request.get_transaction_id());
+        for value in attrs {
+            builder = builder.attr(value);
+        }
+        builder = match integrity {
+            Some(Integrity::SHA1(key)) => builder.message_integrity(key),
+            Some(Integrity::SHA256(key, length)) => builder.message_integrity_sha256(key, *length),
+            None => builder,
+        };
+        if request.get_raw_attr_value(Attribute::Fingerprint).is_some() {
+            builder = builder.fingerprint();
+        }
+        builder.build().ok()
+    }
+
+    fn error_response(
+        request: &Message,
+        error_code: ErrorCode,
+        mut attrs: Vec<AttributeValue>,
+        integrity: Option<&Integrity>,
+    ) -> Option<Message> {
+        attrs.insert(
+            0,
+            AttributeValue::ErrorCode {
+                code: error_code.code(),
+                reason: String::from(error_code.reason()),
+            },
+        );
+        Self::response(request, Class::ErrorResponse, attrs, integrity)
+    }
+}
+
+impl RequestHandler for BindingRequestHandler {
+    fn handle(&mut self, request: &Message, source: SocketAddr) -> Option<Message> {
+        if request.get_method() != Method::Binding {
+            return None;
+        }
+
+        let integrity = match self.authenticate(request) {
+            Ok(integrity) => integrity,
+            // RFC8489: Error responses to unauthenticated requests do not carry MESSAGE-INTEGRITY.
+            Err(error_code) => return Self::error_response(request, error_code, vec![], None),
+        };
+
+        // RFC8489: Once the request is authenticated, unknown comprehension-required attributes are rejected with 420.
+        // RFC8445: The agent answering a connectivity check handles the ICE attributes itself, e.g. by nominating
+        // the pair of a request with USE-CANDIDATE, so they are not unknown.
+        let unknown_attrs = request.get_unknown_comprehension_required_attrs_except(&[
+            ATTR_PRIORITY,
+            ATTR_USE_CANDIDATE,
+            ATTR_ICE_CONTROLLED,
+            ATTR_ICE_CONTROLLING,
+        ]);
+        if !unknown_attrs.is_empty() {
+            return Self::error_response(
+                request,
+                ErrorCode::UnknownAttribute(String::from("Unknown Attribute")),
+                vec![AttributeValue::UnknownAttributes(unknown_attrs)],
+                integrity.as_ref(),
+            );
+        }
+
+        Self::response(
+            request,
+            Class::SuccessResponse,
+            vec![AttributeValue::XORMappedAddress(source)],
+            integrity.as_ref(),
+        )
+    }
+}
+
+impl fmt::Debug for BindingRequestHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BindingRequestHandler")
+            .field("username", &self.username)
+            .field("key", &self.key.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+// The integrity attribute of an authenticated request, which the response is signed with.
+enum Integrity {
+    SHA1(Vec<u8>),
+    SHA256(Vec<u8>, usize),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source() -> SocketAddr {
+        "192.0.2.1:32853".parse().unwrap()
+    }
+
+    #[test]
+    fn binding_requests_are_answered_with_the_source_address() {
+        let mut handler = BindingRequestHandler::new();
+        let mut request = Message::new(Method::Binding, Class::Request, None);
+        request.add_fingerprint();
+        let request = Message::from_raw(&request.to_raw()).unwrap();
+
+        let response = handler.handle(&request, source()).unwrap();
+        let response = Message::from_raw(&response.to_raw()).unwrap();
+        assert_eq!(response.get_class(), Class::SuccessResponse);
+        assert_eq!(response.get_transaction_id(), request.get_transaction_id());
+        assert_eq!(Attribute::get_xor_mapped_address(&response), Some(source()));
+        assert!(response
+            .get_raw_attr_value(Attribute::Fingerprint)
+            .is_some());
+
+        let allocate = Message::new(Method::Allocate, Class::Request, None);
+        assert!(handler.handle(&allocate, source()).is_none());
+    }
+
+    #[test]
+    fn short_term_credentials_are_verified() {
+        let mut handler =
+            BindingRequestHandler::with_short_term_credentials("local:remote", "password").unwrap();
+        let request = |username: &str, key: &[u8]| {
+            let mut msg = Message::new(Method::Binding, Class::Request, None);
+            msg.add(AttributeValue::Username(String::from(username)))
+                .unwrap();
+            msg.add_message_integrity(key);
+            Message::from_raw(&msg.to_raw()).unwrap()
+        };
+        let error_code = |response: Option<Message>| {
+            Attribute::get_error_code(&response.unwrap()).map(|e| e.code())
+        };
+
+        let response = handler
+            .handle(&request("local:remote", b"password"), source())
+            .unwrap();
+        assert_eq!(response.get_class(), Class::SuccessResponse);
+        assert!(response.verify_integrity(b"password").is_ok());
+
+        let unsigned = Message::new(Method::Binding, Class::Request, None);
+        assert_eq!(error_code(handler.handle(&unsigned, source())), Some(400));
+        assert_eq!(
+            error_code(handler.handle(&request("other:remote", b"password"), source())),
+            Some(401)
+        );
+        let response = handler.handle(&request("local:remote", b"wrong"), source());
+        assert!(response
+            .as_ref()
+            .unwrap()
+            .get_raw_attr_value(Attribute::MessageIntegrity)
+            .is_none());
+        assert_eq!(error_code(response), Some(401));
+    }
+
+    #[test]
+    fn ice_connectivity_checks_are_answered() {
+        let mut handler =
+            BindingRequestHandler::with_short_term_credentials("local:remote", "password").unwrap();
+        let check = |attrs: Vec<AttributeValue>| {
+            let mut builder = MessageBuilder::new(Method::Binding, Class::Request)
+                .attr(AttributeValue::Username(String::from("local:remote")));
+            for value in attrs {
+                builder = builder.attr(value);
+            }
+            let msg = builder.message_integrity(b"password").build().unwrap();
+            Message::from_raw(&msg.to_raw()).unwrap()
+        };
+        let priority = AttributeValue::Opaque(
+            Attribute::from_u16(ATTR_PRIORITY),
+            0x6e0001ffu32.to_be_bytes().to_vec(),
+        );
+
+        let response = handler
+            .handle(
+                &check(vec![
+                    priority.clone(),
+                    AttributeValue::Opaque(Attribute::from_u16(ATTR_USE_CANDIDATE), vec![]),
+                    AttributeValue::Opaque(
+                        Attribute::from_u16(ATTR_ICE_CONTROLLING),
+                        0x0123456789abcdefu64.to_be_bytes().to_vec(),
+                    ),
+                ]),
+                source(),
+            )
+            .unwrap();
+        assert_eq!(response.get_class(), Class::SuccessResponse);
+        assert_eq!(Attribute::get_xor_mapped_address(&response), Some(source()));
+        assert!(response.verify_integrity(b"password").is_ok());
+
+        let unknown = Attribute::from_u16(0x7fff);
+        let response = handler
+            .handle(
+                &check(vec![priority, AttributeValue::Opaque(unknown, vec![])]),
+                source(),
+            )
+            .unwrap();
+        assert_eq!(
+            Attribute::get_error_code(&response).map(|e| e.code()),
+            Some(420)
+        );
+        assert_eq!(
+            Attribute::get_unknown_attributes(&response),
+            Some(vec![unknown])
+        );
+    }
+}